@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS milk_buckets (
+    name TEXT PRIMARY KEY,
+    tokens BIGINT NOT NULL,
+    max BIGINT NOT NULL,
+    refill BIGINT NOT NULL,
+    interval_ms BIGINT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS milk_ledger (
+    id BIGSERIAL PRIMARY KEY,
+    kind TEXT NOT NULL,
+    units BIGINT NOT NULL,
+    client TEXT NOT NULL,
+    at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS milk_ledger_at ON milk_ledger (at);
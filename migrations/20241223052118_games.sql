@@ -0,0 +1,19 @@
+CREATE TABLE IF NOT EXISTS games (
+    id UUID PRIMARY KEY,
+    width INT NOT NULL,
+    height INT NOT NULL,
+    connect INT NOT NULL,
+    -- One character per cell, row by row from the top: '.' empty, 'm' milk, 'c' cookie
+    start TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS game_moves (
+    game_id UUID NOT NULL REFERENCES games (id) ON DELETE CASCADE,
+    seq INT NOT NULL,
+    team TEXT NOT NULL,
+    col INT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (game_id, seq)
+);
@@ -0,0 +1 @@
+ALTER TABLE games ADD COLUMN IF NOT EXISTS strict BOOLEAN NOT NULL DEFAULT false;
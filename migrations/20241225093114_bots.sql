@@ -0,0 +1,30 @@
+CREATE TABLE IF NOT EXISTS bots (
+    name TEXT PRIMARY KEY,
+    -- 'random', 'greedy', 'search' or 'http'
+    kind TEXT NOT NULL,
+    depth INT,
+    url TEXT,
+    rating DOUBLE PRECISION NOT NULL DEFAULT 1500,
+    wins INT NOT NULL DEFAULT 0,
+    draws INT NOT NULL DEFAULT 0,
+    losses INT NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS bot_matches (
+    id BIGSERIAL PRIMARY KEY,
+    milk TEXT NOT NULL REFERENCES bots (name) ON DELETE CASCADE,
+    cookie TEXT NOT NULL REFERENCES bots (name) ON DELETE CASCADE,
+    -- NULL for a draw
+    winner TEXT,
+    moves INT NOT NULL,
+    -- Whether the loser made an illegal move or didn't answer
+    forfeit BOOLEAN NOT NULL DEFAULT false,
+    played_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+INSERT INTO bots (name, kind) VALUES
+    ('random', 'random'),
+    ('greedy', 'greedy'),
+    ('search', 'search')
+ON CONFLICT DO NOTHING;
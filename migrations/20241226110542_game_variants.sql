@@ -0,0 +1,4 @@
+ALTER TABLE games ADD COLUMN IF NOT EXISTS variant TEXT NOT NULL DEFAULT 'classic';
+ALTER TABLE game_moves ADD COLUMN IF NOT EXISTS action TEXT NOT NULL DEFAULT 'drop';
+-- Where a no-gravity piece went, counting from 1 at the top
+ALTER TABLE game_moves ADD COLUMN IF NOT EXISTS row INT;
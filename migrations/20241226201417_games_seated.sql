@@ -0,0 +1 @@
+ALTER TABLE games ADD COLUMN IF NOT EXISTS seated BOOLEAN NOT NULL DEFAULT false;
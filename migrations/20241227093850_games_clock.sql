@@ -0,0 +1,3 @@
+-- Seconds per move and per team for the whole game, NULL when not limited
+ALTER TABLE games ADD COLUMN IF NOT EXISTS move_time INT;
+ALTER TABLE games ADD COLUMN IF NOT EXISTS total_time INT;
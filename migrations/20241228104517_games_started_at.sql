@@ -0,0 +1,2 @@
+-- When the game was last started over, for its clock
+ALTER TABLE games ADD COLUMN IF NOT EXISTS started_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP;
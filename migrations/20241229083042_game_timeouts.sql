@@ -0,0 +1,8 @@
+-- Teams that ran out of time, after how many of the game's moves
+CREATE TABLE IF NOT EXISTS game_timeouts (
+    game_id UUID NOT NULL REFERENCES games (id) ON DELETE CASCADE,
+    team TEXT NOT NULL,
+    moves INT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (game_id, team)
+);
@@ -0,0 +1,2 @@
+-- Whether the server plays the other team, so it keeps doing so after a restart
+ALTER TABLE games ADD COLUMN IF NOT EXISTS auto_reply BOOLEAN NOT NULL DEFAULT false;
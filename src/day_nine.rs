@@ -1,109 +1,1369 @@
-use std::{ops::DerefMut, sync::Mutex, time::Duration};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Display,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
 use actix_web::{
-    post,
-    web::{Data, Json},
-    HttpRequest, HttpResponse, Responder,
+    get, post, put,
+    web::{Bytes, Data, Json, Query},
+    HttpMessage, HttpRequest, HttpResponse, Responder,
 };
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use futures_util::future::{select, Either};
 use leaky_bucket::RateLimiter;
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sqlx::PgPool;
+use tokio::sync::watch;
 
 pub struct MilkCrate {
-    pub ratelimit: Mutex<RateLimiter>,
+    bucket: Bucket,
+    pub policy: WaitPolicy,
+    pub ledger: Ledger,
+    waiting: AtomicUsize,
+}
+
+/// Controls whether a withdrawal waits for milk instead of being rejected straight away.
+#[derive(Debug, Clone)]
+pub struct WaitPolicy {
+    /// How long to wait when the request doesn't say. `None` rejects immediately.
+    pub default_wait: Option<Duration>,
+    /// Upper bound on any wait, including ones asked for with `Prefer: wait=N`.
+    pub max_wait: Duration,
+    /// How many requests may be waiting for milk at the same time.
+    pub max_queue: usize,
+}
+
+impl Default for WaitPolicy {
+    fn default() -> Self {
+        Self {
+            default_wait: None,
+            max_wait: Duration::from_secs(10),
+            max_queue: 32,
+        }
+    }
+}
+
+/// Size and refill rate of the milk crate.
+#[derive(Debug, Clone)]
+pub struct BucketConfig {
+    pub initial: usize,
+    pub max: usize,
+    pub refill: usize,
+    pub interval: Duration,
+}
+
+impl Default for BucketConfig {
+    fn default() -> Self {
+        Self {
+            initial: 5,
+            max: 5,
+            refill: 1,
+            interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Where the milk tokens live.
+enum Bucket {
+    /// In this process only.
+    Local(Mutex<LocalBucket>),
+    /// In Postgres, shared by every instance using the same database.
+    Postgres(PgBucket),
+}
+
+struct LocalBucket {
+    config: BucketConfig,
+    /// Waiting withdrawals watch this, so a refill or config change that swaps in a new
+    /// limiter moves them over instead of leaving them to drain the old one.
+    limiter: watch::Sender<Arc<RateLimiter>>,
+}
+
+impl LocalBucket {
+    fn new(config: BucketConfig) -> Self {
+        Self {
+            limiter: watch::channel(Arc::new(build_rate_limiter(&config))).0,
+            config,
+        }
+    }
+
+    /// The milk in the crate right now. The limiter only adds refills that are due when an
+    /// acquire can't be met from what it holds, so ask for more than it ever could first.
+    fn balance(&self) -> usize {
+        let limiter = self.limiter.borrow();
+        limiter.try_acquire(limiter.max() + 1);
+        limiter.balance()
+    }
+
+    /// Swaps in a limiter for `config` holding `initial` tokens.
+    fn replace(&mut self, config: BucketConfig, initial: usize) {
+        let limiter = build_rate_limiter(&BucketConfig {
+            initial,
+            ..config.clone()
+        });
+        self.limiter.send_replace(Arc::new(limiter));
+        self.config = config;
+    }
 }
 
 impl MilkCrate {
-    pub fn new() -> Self {
+    /// Picks the backend from `MILK_BACKEND`, either `local` (the default) or `postgres`.
+    /// The ledger is kept in Postgres as well when `MILK_LEDGER=postgres`, requests that
+    /// don't say how long to wait wait `MILK_DEFAULT_WAIT` seconds, and at most
+    /// `MILK_MAX_QUEUE` of them wait at once.
+    pub async fn from_env(pool: PgPool) -> Result<Self, sqlx::Error> {
+        let config = BucketConfig::default();
+        let mut policy = WaitPolicy::default();
+        if let Some(secs) = env_setting("MILK_DEFAULT_WAIT") {
+            policy.default_wait = Some(Duration::from_secs(secs));
+        }
+        if let Some(max_queue) = env_setting("MILK_MAX_QUEUE") {
+            policy.max_queue = max_queue;
+        }
+        let ledger = match std::env::var("MILK_LEDGER").as_deref() {
+            Ok("postgres") => Ledger::with_pool(pool.clone()).await?,
+            _ => Ledger::default(),
+        };
+        let bucket = match std::env::var("MILK_BACKEND").as_deref() {
+            Ok("postgres") => Bucket::Postgres(PgBucket::new(pool, config).await?),
+            _ => Bucket::Local(Mutex::new(LocalBucket::new(config))),
+        };
+        Ok(Self::with_bucket(bucket, policy, ledger))
+    }
+
+    fn with_bucket(bucket: Bucket, policy: WaitPolicy, ledger: Ledger) -> Self {
         println!("made new milk crate");
         Self {
-            ratelimit: Mutex::new(build_rate_limiter()),
+            bucket,
+            policy,
+            ledger,
+            waiting: AtomicUsize::new(0),
         }
     }
 }
 
 impl MilkCrate {
-    fn get(&self) -> bool {
-        let mc = self.ratelimit.lock().unwrap();
-        let any_left = mc.try_acquire(1);
+    async fn get(&self, units: usize, wait: Option<Duration>) -> Result<bool, sqlx::Error> {
+        if self.try_get(units).await? {
+            return Ok(true);
+        }
+
+        let Some(wait) = self.wait(wait) else {
+            return Ok(false);
+        };
+        let Some(_slot) = self.enqueue() else {
+            println!("milk queue is full");
+            return Ok(false);
+        };
 
-        any_left
+        match &self.bucket {
+            Bucket::Local(mc) => {
+                // Watch the limiter rather than hold the lock while waiting
+                let mut limiters = mc.lock().unwrap().limiter.subscribe();
+                let acquired = async {
+                    loop {
+                        let limiter = limiters.borrow_and_update().clone();
+                        let acquire = std::pin::pin!(limiter.acquire(units));
+                        let changed = std::pin::pin!(limiters.changed());
+                        // Dropping an unfinished acquire hands back what it had taken so far
+                        match select(acquire, changed).await {
+                            Either::Left(_) => return,
+                            Either::Right((Ok(()), _)) => continue,
+                            // The crate's gone, so nothing will swap the limiter out again
+                            Either::Right((Err(_), acquire)) => return acquire.await,
+                        }
+                    }
+                };
+                Ok(tokio::time::timeout(wait, acquired).await.is_ok())
+            }
+            Bucket::Postgres(pg) => {
+                let interval = pg.config().await?.interval;
+                let deadline = tokio::time::Instant::now() + wait;
+                loop {
+                    let now = tokio::time::Instant::now();
+                    if now >= deadline {
+                        return Ok(false);
+                    }
+                    tokio::time::sleep(interval.min(deadline - now)).await;
+                    if pg.try_acquire(units).await? {
+                        return Ok(true);
+                    }
+                }
+            }
+        }
     }
 
-    fn refill(&self) {
-        let mut mc = self.ratelimit.lock().expect("failed to lock milk crate");
-        *mc.deref_mut() = build_rate_limiter();
+    async fn try_get(&self, units: usize) -> Result<bool, sqlx::Error> {
+        match &self.bucket {
+            Bucket::Local(mc) => Ok(mc.lock().unwrap().limiter.borrow().try_acquire(units)),
+            Bucket::Postgres(pg) => pg.try_acquire(units).await,
+        }
     }
+
+    async fn config(&self) -> Result<BucketConfig, sqlx::Error> {
+        match &self.bucket {
+            Bucket::Local(mc) => Ok(mc.lock().unwrap().config.clone()),
+            Bucket::Postgres(pg) => pg.config().await,
+        }
+    }
+
+    /// Applies `changes` to the size and refill rate, keeping the milk that's already in the
+    /// crate. The bucket stays locked from reading the old config to writing the new one, so
+    /// changes made at the same time don't undo each other.
+    async fn set_config(&self, changes: &ConfigBody) -> Result<BucketConfig, ConfigError> {
+        match &self.bucket {
+            Bucket::Local(mc) => {
+                let mut mc = mc.lock().expect("failed to lock milk crate");
+                let mut config = mc.config.clone();
+                changes.apply(&mut config)?;
+                let balance = mc.balance().min(config.max);
+                mc.replace(config.clone(), balance);
+                Ok(config)
+            }
+            Bucket::Postgres(pg) => pg.set_config(changes).await,
+        }
+    }
+
+    /// How long a request that asked to wait `preferred` may, or `None` if it shouldn't.
+    fn wait(&self, preferred: Option<Duration>) -> Option<Duration> {
+        preferred
+            .or(self.policy.default_wait)
+            .map(|wait| wait.min(self.policy.max_wait))
+            .filter(|wait| !wait.is_zero())
+    }
+
+    fn enqueue(&self) -> Option<QueueSlot<'_>> {
+        self.waiting
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < self.policy.max_queue).then_some(n + 1)
+            })
+            .ok()?;
+        Some(QueueSlot(&self.waiting))
+    }
+
+    async fn refill(&self) -> Result<(), sqlx::Error> {
+        match &self.bucket {
+            Bucket::Local(mc) => {
+                // Filled to the brim, like the Postgres bucket
+                let mut mc = mc.lock().expect("failed to lock milk crate");
+                let config = mc.config.clone();
+                let max = config.max;
+                mc.replace(config, max);
+                Ok(())
+            }
+            Bucket::Postgres(pg) => pg.reset().await,
+        }
+    }
+}
+
+/// A place in the milk queue, given back when dropped.
+struct QueueSlot<'a>(&'a AtomicUsize);
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// The setting `name` from the environment, if it's set and can be parsed.
+fn env_setting<T: FromStr>(name: &str) -> Option<T>
+where
+    T::Err: Display,
+{
+    let value = std::env::var(name).ok()?;
+    value
+        .parse()
+        .map_err(|e| println!("Ignoring {name}={value}: {e}"))
+        .ok()
+}
+
+/// Reads the `wait` preference (RFC 7240) from a `Prefer` header, e.g. `Prefer: wait=5`.
+fn preferred_wait(req: &HttpRequest) -> Option<Duration> {
+    req.headers()
+        .get_all("Prefer")
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split([',', ';']))
+        .find_map(|pref| {
+            let (name, value) = pref.split_once('=')?;
+            if !name.trim().eq_ignore_ascii_case("wait") {
+                return None;
+            }
+            let secs: u64 = value.trim().trim_matches('"').parse().ok()?;
+            Some(Duration::from_secs(secs))
+        })
 }
 
-fn build_rate_limiter() -> RateLimiter {
+fn build_rate_limiter(config: &BucketConfig) -> RateLimiter {
     RateLimiter::builder()
-        .initial(5)
-        .interval(Duration::from_secs(1))
-        .max(5)
+        .initial(config.initial)
+        .interval(config.interval)
+        .refill(config.refill)
+        .max(config.max)
         .build()
 }
 
+const BUCKET_NAME: &str = "milk";
+
+/// A token bucket kept in the `milk_buckets` table, refilled the same way as [`RateLimiter`]:
+/// `refill` tokens for every whole `interval` that has passed, never going above `max`.
+///
+/// The row holds the size and refill rate too, so every instance sees config changes.
+struct PgBucket {
+    pool: PgPool,
+}
+
+#[derive(sqlx::FromRow)]
+struct BucketRow {
+    tokens: i64,
+    max: i64,
+    // `refill` on its own would clash with the handler of the same name
+    #[sqlx(rename = "refill")]
+    refill_by: i64,
+    interval_ms: i64,
+    updated_at: DateTime<Utc>,
+    now: DateTime<Utc>,
+}
+
+const SELECT_BUCKET: &str = "SELECT tokens, max, refill, interval_ms, updated_at, now() AS now
+    FROM milk_buckets WHERE name = $1";
+
+impl PgBucket {
+    async fn new(pool: PgPool, config: BucketConfig) -> Result<Self, sqlx::Error> {
+        // Another instance may have made the bucket already, keep its tokens and config
+        sqlx::query(
+            "INSERT INTO milk_buckets (name, tokens, max, refill, interval_ms) VALUES ($1, $2, $3, $4, $5)
+             ON CONFLICT (name) DO NOTHING",
+        )
+        .bind(BUCKET_NAME)
+        .bind(config.initial as i64)
+        .bind(config.max as i64)
+        .bind(config.refill as i64)
+        .bind(config.interval.as_millis() as i64)
+        .execute(&pool)
+        .await?;
+
+        Ok(Self { pool })
+    }
+
+    async fn config(&self) -> Result<BucketConfig, sqlx::Error> {
+        let row: BucketRow = sqlx::query_as(SELECT_BUCKET)
+            .bind(BUCKET_NAME)
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(row.config())
+    }
+
+    async fn try_acquire(&self, units: usize) -> Result<bool, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        // The row lock serialises withdrawals from every instance
+        let row: BucketRow = sqlx::query_as(&format!("{SELECT_BUCKET} FOR UPDATE"))
+            .bind(BUCKET_NAME)
+            .fetch_one(&mut *tx)
+            .await?;
+
+        let (mut tokens, updated_at) = row.refilled();
+        let granted = tokens >= units as i64;
+        if granted {
+            tokens -= units as i64;
+        }
+
+        sqlx::query("UPDATE milk_buckets SET tokens = $2, updated_at = $3 WHERE name = $1")
+            .bind(BUCKET_NAME)
+            .bind(tokens)
+            .bind(updated_at)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(granted)
+    }
+
+    async fn set_config(&self, changes: &ConfigBody) -> Result<BucketConfig, ConfigError> {
+        let mut tx = self.pool.begin().await?;
+        let row: BucketRow = sqlx::query_as(&format!("{SELECT_BUCKET} FOR UPDATE"))
+            .bind(BUCKET_NAME)
+            .fetch_one(&mut *tx)
+            .await?;
+        let mut config = row.config();
+        changes.apply(&mut config)?;
+
+        // Settle the refills owed under the old rate before switching over
+        let (tokens, updated_at) = row.refilled();
+        sqlx::query(
+            "UPDATE milk_buckets SET tokens = $2, updated_at = $3, max = $4, refill = $5, interval_ms = $6
+             WHERE name = $1",
+        )
+        .bind(BUCKET_NAME)
+        .bind(tokens.min(config.max as i64))
+        .bind(updated_at)
+        .bind(config.max as i64)
+        .bind(config.refill as i64)
+        .bind(config.interval.as_millis() as i64)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(config)
+    }
+
+    async fn reset(&self) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE milk_buckets SET tokens = max, updated_at = now() WHERE name = $1")
+            .bind(BUCKET_NAME)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+impl BucketRow {
+    fn config(&self) -> BucketConfig {
+        BucketConfig {
+            initial: self.max as usize,
+            max: self.max as usize,
+            refill: self.refill_by as usize,
+            interval: Duration::from_millis(self.interval_ms as u64),
+        }
+    }
+
+    /// Tokens and refill time after catching up on the intervals since the last update.
+    fn refilled(&self) -> (i64, DateTime<Utc>) {
+        let interval_ms = self.interval_ms.max(1);
+        let elapsed_ms = (self.now - self.updated_at).num_milliseconds().max(0);
+        let steps = elapsed_ms / interval_ms;
+        if steps == 0 {
+            return (self.tokens, self.updated_at);
+        }
+
+        let tokens = (self.tokens + steps * self.refill_by).min(self.max);
+        if tokens == self.max {
+            // A full bucket doesn't bank time towards the next refill
+            return (tokens, self.now);
+        }
+        let updated_at = self.updated_at + chrono::Duration::milliseconds(steps * interval_ms);
+        (tokens, updated_at)
+    }
+}
+
+/// The parts of [`BucketConfig`] that can be read and changed through `/9/config`.
 #[derive(Debug, Deserialize, Serialize)]
-// #[serde(untagged)]
-#[serde(rename_all = "snake_case")]
-enum Convert {
-    Liters(f32),
-    Gallons(f32),
-    Litres(f32),
-    Pints(f32),
+struct ConfigBody {
+    capacity: Option<usize>,
+    refill: Option<usize>,
+    interval_ms: Option<u64>,
 }
 
-const LITER_TO_GALLON: f32 = 0.26417206;
-const LITRES_TO_PINT: f32 = 1.759756969;
+impl ConfigBody {
+    /// The most milk the crate can hold or be refilled with at once.
+    const MAX_TOKENS: usize = 1_000_000_000;
+    /// The longest the crate can go between refills, a day.
+    const MAX_INTERVAL_MS: u64 = 24 * 60 * 60 * 1000;
 
-#[post("/9/milk")]
-pub async fn milk(
-    body: Option<Json<Convert>>,
-    milk_crate: Data<MilkCrate>,
-    req: HttpRequest,
-) -> impl Responder {
-    let any_left = milk_crate.get();
-    if !any_left {
-        return HttpResponse::TooManyRequests().body("No milk available\n");
+    /// Changes what the body sets in `config`, leaving the rest as it is.
+    fn apply(&self, config: &mut BucketConfig) -> Result<(), ConfigError> {
+        if let Some(capacity) = self.capacity {
+            config.max = capacity;
+        }
+        if let Some(amount) = self.refill {
+            config.refill = amount;
+        }
+        if let Some(interval_ms) = self.interval_ms {
+            config.interval = Duration::from_millis(interval_ms);
+        }
+
+        let tokens = 1..=Self::MAX_TOKENS;
+        let interval_ms = 1..=Self::MAX_INTERVAL_MS as u128;
+        if !tokens.contains(&config.max)
+            || !tokens.contains(&config.refill)
+            || !interval_ms.contains(&config.interval.as_millis())
+        {
+            return Err(ConfigError::Invalid(format!(
+                "capacity and refill must be between 1 and {}, and interval_ms between 1 and {}",
+                Self::MAX_TOKENS,
+                Self::MAX_INTERVAL_MS
+            )));
+        }
+        Ok(())
     }
+}
 
-    match req.headers().get("Content-Type") {
-        Some(ct) if ct == "application/json" => {
-            let Some(convert) = body else {
-                return HttpResponse::BadRequest().finish();
-            };
-            println!("{convert:?}");
-            let convert = convert.into_inner();
-
-            match convert {
-                Convert::Liters(l) => {
-                    let gallons = l as f32 * LITER_TO_GALLON;
-                    let gallons = Convert::Gallons(gallons);
-                    HttpResponse::Ok().body(serde_json::to_string(&gallons).unwrap())
-                }
-                Convert::Gallons(g) => {
-                    let liters = g as f32 / LITER_TO_GALLON;
-                    let liters = Convert::Liters(liters);
-                    HttpResponse::Ok().body(serde_json::to_string(&liters).unwrap())
+/// Why `/9/config` couldn't be changed.
+enum ConfigError {
+    Invalid(String),
+    Db(sqlx::Error),
+}
+
+impl From<sqlx::Error> for ConfigError {
+    fn from(e: sqlx::Error) -> Self {
+        ConfigError::Db(e)
+    }
+}
+
+#[get("/9/config")]
+pub async fn get_config(milk_crate: Data<MilkCrate>) -> impl Responder {
+    let config = match milk_crate.config().await {
+        Ok(config) => config,
+        Err(e) => {
+            println!("Failed to read milk crate config: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    HttpResponse::Ok().json(ConfigBody {
+        capacity: Some(config.max),
+        refill: Some(config.refill),
+        interval_ms: Some(config.interval.as_millis() as u64),
+    })
+}
+
+#[put("/9/config")]
+pub async fn set_config(milk_crate: Data<MilkCrate>, body: Json<ConfigBody>) -> impl Responder {
+    // Anything left out keeps its current value
+    let config = match milk_crate.set_config(&body).await {
+        Ok(config) => config,
+        Err(ConfigError::Invalid(e)) => return HttpResponse::BadRequest().body(format!("{e}\n")),
+        Err(ConfigError::Db(e)) => {
+            println!("Failed to update milk crate config: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    HttpResponse::Ok().json(ConfigBody {
+        capacity: Some(config.max),
+        refill: Some(config.refill),
+        interval_ms: Some(config.interval.as_millis() as u64),
+    })
+}
+
+/// What happened to the crate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LedgerKind {
+    Withdrawal,
+    Rejection,
+    Refill,
+}
+
+impl LedgerKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            LedgerKind::Withdrawal => "withdrawal",
+            LedgerKind::Rejection => "rejection",
+            LedgerKind::Refill => "refill",
+        }
+    }
+
+    fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "withdrawal" => Some(LedgerKind::Withdrawal),
+            "rejection" => Some(LedgerKind::Rejection),
+            "refill" => Some(LedgerKind::Refill),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct LedgerEntry {
+    kind: LedgerKind,
+    units: usize,
+    client: String,
+    at: DateTime<Utc>,
+}
+
+#[derive(sqlx::FromRow)]
+struct LedgerRow {
+    kind: String,
+    units: i64,
+    client: String,
+    at: DateTime<Utc>,
+}
+
+/// How many entries the in-memory ledger keeps before forgetting the oldest.
+const LEDGER_CAPACITY: usize = 100_000;
+
+/// Everything that happened to the crate, kept in memory and optionally written to Postgres.
+#[derive(Default)]
+pub struct Ledger {
+    entries: Mutex<VecDeque<LedgerEntry>>,
+    pool: Option<PgPool>,
+}
+
+impl Ledger {
+    /// Persists to the `milk_ledger` table, starting from what's already there.
+    pub async fn with_pool(pool: PgPool) -> Result<Self, sqlx::Error> {
+        let rows: Vec<LedgerRow> = sqlx::query_as(
+            "SELECT kind, units, client, at FROM milk_ledger ORDER BY at DESC LIMIT $1",
+        )
+        .bind(LEDGER_CAPACITY as i64)
+        .fetch_all(&pool)
+        .await?;
+
+        let entries = rows
+            .into_iter()
+            .rev()
+            .filter_map(|row| {
+                Some(LedgerEntry {
+                    kind: LedgerKind::from_str(&row.kind)?,
+                    units: row.units as usize,
+                    client: row.client,
+                    at: row.at,
+                })
+            })
+            .collect();
+
+        Ok(Self {
+            entries: Mutex::new(entries),
+            pool: Some(pool),
+        })
+    }
+
+    async fn record(&self, kind: LedgerKind, units: usize, client: &str) {
+        let entry = LedgerEntry {
+            kind,
+            units,
+            client: client.to_string(),
+            at: Utc::now(),
+        };
+
+        if let Some(pool) = &self.pool {
+            let res = sqlx::query(
+                "INSERT INTO milk_ledger (kind, units, client, at) VALUES ($1, $2, $3, $4)",
+            )
+            .bind(entry.kind.as_str())
+            .bind(entry.units as i64)
+            .bind(&entry.client)
+            .bind(entry.at)
+            .execute(pool)
+            .await;
+            if let Err(e) = res {
+                println!("Failed to write milk ledger: {e}");
+            }
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == LEDGER_CAPACITY {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    fn stats(&self) -> Stats {
+        let entries = self.entries.lock().unwrap();
+        Stats::from_entries(entries.iter())
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Counts {
+    withdrawals: usize,
+    units_withdrawn: usize,
+    rejections: usize,
+    refills: usize,
+}
+
+impl Counts {
+    fn add(&mut self, entry: &LedgerEntry) {
+        match entry.kind {
+            LedgerKind::Withdrawal => {
+                self.withdrawals += 1;
+                self.units_withdrawn += entry.units;
+            }
+            LedgerKind::Rejection => self.rejections += 1,
+            LedgerKind::Refill => self.refills += 1,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct MinuteCounts {
+    minute: DateTime<Utc>,
+    #[serde(flatten)]
+    counts: Counts,
+}
+
+#[derive(Debug, Serialize)]
+struct Stats {
+    #[serde(flatten)]
+    totals: Counts,
+    /// Share of withdrawal attempts that were turned away.
+    rejection_rate: f64,
+    clients: BTreeMap<String, Counts>,
+    per_minute: Vec<MinuteCounts>,
+}
+
+impl Stats {
+    fn from_entries<'a>(entries: impl Iterator<Item = &'a LedgerEntry>) -> Self {
+        let mut totals = Counts::default();
+        let mut clients: BTreeMap<String, Counts> = BTreeMap::new();
+        let mut minutes: BTreeMap<DateTime<Utc>, Counts> = BTreeMap::new();
+        for entry in entries {
+            totals.add(entry);
+            clients.entry(entry.client.clone()).or_default().add(entry);
+            let minute = entry
+                .at
+                .duration_trunc(TimeDelta::minutes(1))
+                .unwrap_or(entry.at);
+            minutes.entry(minute).or_default().add(entry);
+        }
+
+        let attempts = totals.withdrawals + totals.rejections;
+        let rejection_rate = if attempts == 0 {
+            0.0
+        } else {
+            totals.rejections as f64 / attempts as f64
+        };
+
+        Self {
+            totals,
+            rejection_rate,
+            clients,
+            per_minute: minutes
+                .into_iter()
+                .map(|(minute, counts)| MinuteCounts { minute, counts })
+                .collect(),
+        }
+    }
+}
+
+/// Who's asking, by the address the request came from.
+fn client_key(req: &HttpRequest) -> String {
+    req.connection_info()
+        .realip_remote_addr()
+        .unwrap_or("unknown")
+        .to_string()
+}
+
+#[get("/9/stats")]
+pub async fn stats(milk_crate: Data<MilkCrate>) -> impl Responder {
+    HttpResponse::Ok().json(milk_crate.ledger.stats())
+}
+
+/// A unit of volume `/9/milk` can convert between.
+///
+/// Every unit knows its size in millilitres, so any pair converts through that.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Unit {
+    name: &'static str,
+    aliases: &'static [&'static str],
+    millilitres: f64,
+}
+
+// The bare customary names keep the meaning the endpoint always gave them:
+// `gallons` are US gallons and `pints` are imperial pints.
+const UNITS: &[Unit] = &[
+    Unit {
+        name: "milliliters",
+        aliases: &["milliliter", "millilitres", "millilitre", "ml"],
+        millilitres: 1.0,
+    },
+    Unit {
+        name: "centiliters",
+        aliases: &["centiliter", "centilitres", "centilitre", "cl"],
+        millilitres: 10.0,
+    },
+    Unit {
+        name: "deciliters",
+        aliases: &["deciliter", "decilitres", "decilitre", "dl"],
+        millilitres: 100.0,
+    },
+    Unit {
+        name: "liters",
+        aliases: &["liter", "l"],
+        millilitres: 1000.0,
+    },
+    Unit {
+        name: "litres",
+        aliases: &["litre"],
+        millilitres: 1000.0,
+    },
+    Unit {
+        name: "fl_oz",
+        aliases: &["us_fl_oz", "fluid_ounces", "fluid_ounce"],
+        millilitres: 29.5735295625,
+    },
+    Unit {
+        name: "cups",
+        aliases: &["cup", "us_cups", "us_cup"],
+        millilitres: 236.5882365,
+    },
+    Unit {
+        name: "us_pints",
+        aliases: &["us_pint"],
+        millilitres: 473.176473,
+    },
+    Unit {
+        name: "quarts",
+        aliases: &["quart", "us_quarts", "us_quart"],
+        millilitres: 946.352946,
+    },
+    Unit {
+        name: "gallons",
+        aliases: &["gallon", "us_gallons", "us_gallon"],
+        millilitres: 3785.411784,
+    },
+    Unit {
+        name: "imperial_fl_oz",
+        aliases: &[],
+        millilitres: 28.4130625,
+    },
+    Unit {
+        name: "pints",
+        aliases: &["pint", "imperial_pints", "imperial_pint"],
+        millilitres: 568.26125,
+    },
+    Unit {
+        name: "imperial_quarts",
+        aliases: &["imperial_quart"],
+        millilitres: 1136.5225,
+    },
+    Unit {
+        name: "imperial_gallons",
+        aliases: &["imperial_gallon"],
+        millilitres: 4546.09,
+    },
+];
+
+impl Unit {
+    fn parse(name: &str) -> Option<Unit> {
+        let name = name.trim().to_ascii_lowercase().replace([' ', '-'], "_");
+        UNITS
+            .iter()
+            .find(|u| u.name == name || u.aliases.contains(&name.as_str()))
+            .copied()
+    }
+
+    /// Where the original endpoint sent this unit when no target was given.
+    fn default_target(&self) -> Option<Unit> {
+        let target = match self.name {
+            "liters" => "gallons",
+            "gallons" => "liters",
+            "litres" => "pints",
+            "pints" => "litres",
+            _ => return None,
+        };
+        Unit::parse(target)
+    }
+
+    fn convert(&self, amount: f64, to: &Unit) -> f64 {
+        amount * self.millilitres / to.millilitres
+    }
+}
+
+/// One `{"liters": 2, "to": "cups"}` request, after parsing.
+#[derive(Debug)]
+struct Conversion {
+    amount: f64,
+    from: Unit,
+    to: Unit,
+    /// The target as the client wrote it, used as the key in the response.
+    to_name: String,
+    round: Option<u32>,
+}
+
+impl Conversion {
+    /// More places than an `f64` holds digits for would only round to noise, or overflow.
+    const MAX_ROUND: u32 = 15;
+
+    /// Reads the amount and target out of a request body. `round` is the fallback when the
+    /// body doesn't ask for rounding itself.
+    fn parse(mut body: Map<String, Value>, round: Option<u32>) -> Result<Self, String> {
+        let round = match body.remove("round") {
+            Some(r) => Some(
+                serde_json::from_value(r)
+                    .map_err(|_| "round must be a number of decimal places")?,
+            ),
+            None => round,
+        };
+        if round.is_some_and(|places| places > Self::MAX_ROUND) {
+            return Err(format!(
+                "round can be at most {} decimal places",
+                Self::MAX_ROUND
+            ));
+        }
+        let to = match body.remove("to") {
+            Some(Value::String(to)) => Some(to),
+            Some(_) => return Err("to must be a unit name".to_string()),
+            None => None,
+        };
+
+        let mut amounts = body.into_iter();
+        let (Some((from, amount)), None) = (amounts.next(), amounts.next()) else {
+            return Err("expected exactly one amount to convert".to_string());
+        };
+        let from = Unit::parse(&from).ok_or(format!("unknown unit {from}"))?;
+        let amount = amount
+            .as_f64()
+            .ok_or(format!("{} must be a number", from.name))?;
+
+        let (to, to_name) = match to {
+            Some(to_name) => (
+                Unit::parse(&to_name).ok_or(format!("unknown unit {to_name}"))?,
+                to_name,
+            ),
+            None => {
+                let to = from
+                    .default_target()
+                    .ok_or(format!("say which unit to convert {} to", from.name))?;
+                (to, to.name.to_string())
+            }
+        };
+
+        Ok(Self {
+            amount,
+            from,
+            to,
+            to_name,
+            round,
+        })
+    }
+
+    fn result(&self) -> Value {
+        let mut value = self.from.convert(self.amount, &self.to);
+        if let Some(places) = self.round {
+            let scale = 10f64.powi(places as i32);
+            value = (value * scale).round() / scale;
+        }
+
+        let mut out = Map::new();
+        out.insert(self.to_name.clone(), value.into());
+        Value::Object(out)
+    }
+}
+
+/// What a `/9/milk` request asked for.
+enum MilkOrder {
+    /// Just milk, no conversion.
+    Plain,
+    /// One conversion, answered with a single object.
+    Single(Map<String, Value>),
+    /// A JSON array of conversions, answered with an array in the same order.
+    Batch(Vec<Map<String, Value>>),
+    /// A conversion was sent but couldn't be read.
+    Invalid,
+}
+
+impl MilkOrder {
+    fn read(req: &HttpRequest, body: &[u8], query: Map<String, Value>) -> Self {
+        match req.mime_type() {
+            // Compare the essence so parameters like `; charset=utf-8` don't get in the way
+            Ok(Some(mime)) if mime.essence_str() == "application/json" => {
+                match serde_json::from_slice(body) {
+                    Ok(Value::Object(map)) => MilkOrder::Single(map),
+                    Ok(Value::Array(items)) => items
+                        .into_iter()
+                        .map(|item| match item {
+                            Value::Object(map) => Some(map),
+                            _ => None,
+                        })
+                        .collect::<Option<Vec<_>>>()
+                        .map_or(MilkOrder::Invalid, MilkOrder::Batch),
+                    _ => MilkOrder::Invalid,
                 }
-                Convert::Litres(l) => {
-                    let pints = l as f32 * LITRES_TO_PINT;
-                    let pints = Convert::Pints(pints);
-                    HttpResponse::Ok().body(serde_json::to_string(&pints).unwrap())
+            }
+            Ok(Some(mime)) if mime.essence_str() == "application/x-www-form-urlencoded" => {
+                std::str::from_utf8(body)
+                    .ok()
+                    .and_then(urlencoded_fields)
+                    .map_or(MilkOrder::Invalid, MilkOrder::Single)
+            }
+            _ if !query.is_empty() => MilkOrder::Single(query),
+            _ => MilkOrder::Plain,
+        }
+    }
+
+    /// Takes the `units` each conversion asks for out of it, `default` where it doesn't say.
+    /// A single object left with nothing to convert is a plain withdrawal of those units.
+    fn take_units(&mut self, default: usize) -> Result<Vec<usize>, String> {
+        match self {
+            MilkOrder::Plain | MilkOrder::Invalid => Ok(vec![default]),
+            MilkOrder::Single(map) => {
+                let units = take_units(map, default)?;
+                if map.keys().all(|key| key == "round") {
+                    *self = MilkOrder::Plain;
                 }
-                Convert::Pints(p) => {
-                    let litres = p as f32 / LITRES_TO_PINT;
-                    let litres = Convert::Litres(litres);
-                    HttpResponse::Ok().body(serde_json::to_string(&litres).unwrap())
+                Ok(vec![units])
+            }
+            MilkOrder::Batch(items) => items
+                .iter_mut()
+                .map(|map| take_units(map, default))
+                .collect(),
+        }
+    }
+}
+
+fn take_units(map: &mut Map<String, Value>, default: usize) -> Result<usize, String> {
+    match map.remove("units") {
+        Some(units) => {
+            serde_json::from_value(units).map_err(|_| "units must be a positive integer".into())
+        }
+        None => Ok(default),
+    }
+}
+
+/// Reads `a=1&b=two` into a JSON object, turning anything that looks like a number into one.
+fn urlencoded_fields(input: &str) -> Option<Map<String, Value>> {
+    let pairs = Query::<Vec<(String, String)>>::from_query(input).ok()?;
+    let fields = pairs
+        .into_inner()
+        .into_iter()
+        .map(|(key, value)| {
+            let value = if let Ok(n) = value.parse::<u64>() {
+                n.into()
+            } else if let Some(n) = value.parse().ok().and_then(serde_json::Number::from_f64) {
+                Value::Number(n)
+            } else {
+                Value::String(value)
+            };
+            (key, value)
+        })
+        .collect();
+    Some(fields)
+}
+
+#[post("/9/milk")]
+pub async fn milk(body: Bytes, milk_crate: Data<MilkCrate>, req: HttpRequest) -> impl Responder {
+    let Some(mut query) = urlencoded_fields(req.query_string()) else {
+        return HttpResponse::BadRequest().body("invalid query string\n");
+    };
+    // `units` and `round` in the query apply to every conversion that doesn't set its own,
+    // anything else in the query is a conversion of its own
+    let default_units = match take_units(&mut query, 1) {
+        Ok(units) => units,
+        Err(e) => return HttpResponse::BadRequest().body(format!("{e}\n")),
+    };
+    let round = match query.remove("round").map(serde_json::from_value::<u32>) {
+        Some(Ok(round)) => Some(round),
+        Some(Err(_)) => {
+            return HttpResponse::BadRequest().body("round must be a number of decimal places\n")
+        }
+        None => None,
+    };
+
+    let mut order = MilkOrder::read(&req, &body, query);
+    let item_units = match order.take_units(default_units) {
+        Ok(units) => units,
+        Err(e) => return HttpResponse::BadRequest().body(format!("{e}\n")),
+    };
+    let Some(units) = item_units
+        .iter()
+        .try_fold(0usize, |total, &units| total.checked_add(units))
+    else {
+        return HttpResponse::BadRequest().body("Too many units\n");
+    };
+    let capacity = match milk_crate.config().await {
+        Ok(config) => config.max,
+        Err(e) => {
+            println!("Failed to read milk crate config: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    if item_units.contains(&0) || units == 0 || units > capacity {
+        return HttpResponse::BadRequest().body(format!(
+            "Can't withdraw {units} units, the crate holds between 1 and {capacity}\n"
+        ));
+    }
+
+    // A conversion that can't be read or done shouldn't cost any milk
+    let mut conversions = vec![];
+    match &mut order {
+        MilkOrder::Single(map) => match Conversion::parse(std::mem::take(map), round) {
+            Ok(conversion) => conversions.push(conversion),
+            Err(e) => return HttpResponse::BadRequest().body(format!("{e}\n")),
+        },
+        MilkOrder::Batch(items) => {
+            for (i, map) in std::mem::take(items).into_iter().enumerate() {
+                match Conversion::parse(map, round) {
+                    Ok(conversion) => conversions.push(conversion),
+                    Err(e) => return HttpResponse::BadRequest().body(format!("item {i}: {e}\n")),
                 }
             }
         }
-        _ => HttpResponse::Ok().body("Milk withdrawn\n"),
+        MilkOrder::Invalid => return HttpResponse::BadRequest().finish(),
+        MilkOrder::Plain => {}
+    }
+
+    let any_left = match milk_crate.get(units, preferred_wait(&req)).await {
+        Ok(any_left) => any_left,
+        Err(e) => {
+            println!("Failed to take milk from the crate: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let client = client_key(&req);
+    if !any_left {
+        milk_crate
+            .ledger
+            .record(LedgerKind::Rejection, units, &client)
+            .await;
+        return HttpResponse::TooManyRequests().body("No milk available\n");
+    }
+    milk_crate
+        .ledger
+        .record(LedgerKind::Withdrawal, units, &client)
+        .await;
+
+    match order {
+        MilkOrder::Plain => HttpResponse::Ok().body("Milk withdrawn\n"),
+        MilkOrder::Invalid => unreachable!("turned away before taking any milk"),
+        MilkOrder::Single(_) => HttpResponse::Ok().json(conversions[0].result()),
+        MilkOrder::Batch(_) => {
+            let results: Vec<Value> = conversions.iter().map(Conversion::result).collect();
+            HttpResponse::Ok().json(results)
+        }
     }
 }
 
 #[post("/9/refill")]
-pub async fn refill(milk_crate: Data<MilkCrate>) -> impl Responder {
-    milk_crate.refill();
+pub async fn refill(milk_crate: Data<MilkCrate>, req: HttpRequest) -> impl Responder {
+    if let Err(e) = milk_crate.refill().await {
+        println!("Failed to refill the milk crate: {e}");
+        return HttpResponse::InternalServerError();
+    }
+    milk_crate
+        .ledger
+        .record(LedgerKind::Refill, 0, &client_key(&req))
+        .await;
     HttpResponse::Ok()
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bucket_refill() {
+        let start = Utc::now();
+        let row = |tokens, elapsed_ms| BucketRow {
+            tokens,
+            max: 5,
+            refill_by: 1,
+            interval_ms: 1000,
+            updated_at: start,
+            now: start + chrono::Duration::milliseconds(elapsed_ms),
+        };
+
+        assert_eq!(row(0, 900).refilled(), (0, start));
+        assert_eq!(
+            row(0, 2500).refilled(),
+            (2, start + chrono::Duration::seconds(2))
+        );
+        let full = row(4, 60_000);
+        assert_eq!(full.refilled(), (5, full.now));
+    }
+
+    #[tokio::test]
+    async fn test_config_keeps_refills() {
+        let local = LocalBucket::new(BucketConfig {
+            initial: 0,
+            max: 5,
+            refill: 1,
+            interval: Duration::from_millis(50),
+        });
+        let milk_crate = MilkCrate::with_bucket(
+            Bucket::Local(Mutex::new(local)),
+            WaitPolicy::default(),
+            Ledger::default(),
+        );
+
+        // Idle long enough for two refills, without anyone asking for milk
+        tokio::time::sleep(Duration::from_millis(120)).await;
+        let changes = ConfigBody {
+            capacity: Some(10),
+            refill: None,
+            interval_ms: Some(60_000),
+        };
+        assert!(milk_crate.set_config(&changes).await.is_ok());
+
+        let Bucket::Local(mc) = &milk_crate.bucket else {
+            unreachable!()
+        };
+        assert!(mc.lock().unwrap().limiter.borrow().balance() >= 2);
+    }
+
+    #[test]
+    fn test_preferred_wait() {
+        let wait = |prefer: &str| {
+            let req = actix_web::test::TestRequest::default()
+                .insert_header(("Prefer", prefer))
+                .to_http_request();
+            preferred_wait(&req)
+        };
+        assert_eq!(wait("wait=5"), Some(Duration::from_secs(5)));
+        assert_eq!(
+            wait("respond-async, Wait = \"3\""),
+            Some(Duration::from_secs(3))
+        );
+        assert_eq!(wait("wait=soon"), None);
+        assert_eq!(wait("wait=-1"), None);
+        assert_eq!(wait("wait"), None);
+        assert_eq!(wait("handling=lenient"), None);
+
+        let milk_crate = MilkCrate::with_bucket(
+            Bucket::Local(Mutex::new(LocalBucket::new(BucketConfig::default()))),
+            WaitPolicy {
+                default_wait: Some(Duration::from_secs(2)),
+                ..WaitPolicy::default()
+            },
+            Ledger::default(),
+        );
+        let max_wait = milk_crate.policy.max_wait;
+        assert_eq!(milk_crate.wait(Some(max_wait * 10)), Some(max_wait));
+        assert_eq!(milk_crate.wait(None), Some(Duration::from_secs(2)));
+        assert_eq!(milk_crate.wait(Some(Duration::ZERO)), None);
+    }
+
+    #[tokio::test]
+    async fn test_full_queue() {
+        let empty = LocalBucket::new(BucketConfig {
+            initial: 0,
+            interval: Duration::from_secs(60),
+            ..BucketConfig::default()
+        });
+        let milk_crate = MilkCrate::with_bucket(
+            Bucket::Local(Mutex::new(empty)),
+            WaitPolicy::default(),
+            Ledger::default(),
+        );
+        let max_queue = milk_crate.policy.max_queue;
+        let slots: Vec<_> = (0..max_queue)
+            .map(|_| milk_crate.enqueue().unwrap())
+            .collect();
+
+        // The next request is turned away at once instead of waiting its 5 seconds
+        assert!(milk_crate.enqueue().is_none());
+        let asked = std::time::Instant::now();
+        let got = milk_crate.get(1, Some(Duration::from_secs(5))).await;
+        assert!(matches!(got, Ok(false)));
+        assert!(asked.elapsed() < Duration::from_secs(1));
+
+        drop(slots);
+        assert!(milk_crate.enqueue().is_some());
+    }
+
+    #[actix_web::test]
+    async fn test_invalid_order_is_free() {
+        let milk_crate = MilkCrate::with_bucket(
+            Bucket::Local(Mutex::new(LocalBucket::new(BucketConfig::default()))),
+            WaitPolicy::default(),
+            Ledger::default(),
+        );
+        let app = actix_web::test::init_service(
+            actix_web::App::new()
+                .app_data(Data::new(milk_crate))
+                .service(milk),
+        )
+        .await;
+        let order = |body: &'static str| {
+            actix_web::test::TestRequest::post()
+                .uri("/9/milk")
+                .insert_header(("Content-Type", "application/json"))
+                .set_payload(body)
+                .to_request()
+        };
+
+        for _ in 0..10 {
+            let res = actix_web::test::call_service(&app, order("{\"liters\": ")).await;
+            assert_eq!(res.status(), actix_web::http::StatusCode::BAD_REQUEST);
+        }
+        // The crate holds 5, and none of it went on the broken orders
+        for _ in 0..5 {
+            let res = actix_web::test::call_service(&app, order("{\"liters\": 1}")).await;
+            assert!(res.status().is_success());
+        }
+    }
+
+    #[test]
+    fn test_conversion() {
+        let parse = |body: Value| match body {
+            Value::Object(body) => Conversion::parse(body, None),
+            _ => unreachable!(),
+        };
+
+        let gallons = parse(serde_json::json!({"liters": 1})).unwrap().result();
+        assert!((gallons["gallons"].as_f64().unwrap() - 0.264172).abs() < 1e-6);
+        let litres = parse(serde_json::json!({"pints": 1})).unwrap().result();
+        assert!((litres["litres"].as_f64().unwrap() - 0.568261).abs() < 1e-6);
+        let cups = parse(serde_json::json!({"liters": 2, "to": "cups", "round": 2}))
+            .unwrap()
+            .result();
+        assert_eq!(cups, serde_json::json!({"cups": 8.45}));
+
+        let cup = parse(serde_json::json!({"liter": 1, "to": "cup", "round": 3}))
+            .unwrap()
+            .result();
+        assert_eq!(cup, serde_json::json!({"cup": 4.227}));
+
+        assert!(parse(serde_json::json!({"liters": 1, "gallons": 2})).is_err());
+        assert!(parse(serde_json::json!({"ml": 250})).is_err());
+        assert!(parse(serde_json::json!({"liters": "one"})).is_err());
+        assert!(parse(serde_json::json!({"liters": 1, "round": 15})).is_ok());
+        assert!(parse(serde_json::json!({"liters": 1, "round": 400})).is_err());
+        let Value::Object(body) = serde_json::json!({"liters": 1}) else {
+            unreachable!()
+        };
+        assert!(Conversion::parse(body, Some(400)).is_err());
+    }
+
+    #[test]
+    fn test_units_only_order() {
+        let fields = |body: Value| match body {
+            Value::Object(body) => body,
+            _ => unreachable!(),
+        };
+
+        let mut order = MilkOrder::Single(fields(serde_json::json!({"units": 3})));
+        assert_eq!(order.take_units(1), Ok(vec![3]));
+        assert!(matches!(order, MilkOrder::Plain));
+
+        let mut order = MilkOrder::Single(urlencoded_fields("units=2&round=1").unwrap());
+        assert_eq!(order.take_units(1), Ok(vec![2]));
+        assert!(matches!(order, MilkOrder::Plain));
+
+        let mut order = MilkOrder::Single(fields(serde_json::json!({"units": 2, "liters": 1})));
+        assert_eq!(order.take_units(1), Ok(vec![2]));
+        assert!(matches!(order, MilkOrder::Single(_)));
+    }
+
+    #[test]
+    fn test_urlencoded_fields() {
+        let fields = urlencoded_fields("liters=2&to=cups&units=3&round=1.5").unwrap();
+        assert_eq!(
+            Value::Object(fields),
+            serde_json::json!({"liters": 2, "to": "cups", "units": 3, "round": 1.5})
+        );
+    }
+
+    #[test]
+    fn test_stats() {
+        let start = Utc::now().duration_trunc(TimeDelta::minutes(1)).unwrap();
+        let entry = |kind, units, client: &str, secs| LedgerEntry {
+            kind,
+            units,
+            client: client.to_string(),
+            at: start + TimeDelta::seconds(secs),
+        };
+        let entries = [
+            entry(LedgerKind::Withdrawal, 3, "a", 0),
+            entry(LedgerKind::Withdrawal, 1, "b", 10),
+            entry(LedgerKind::Rejection, 2, "a", 20),
+            entry(LedgerKind::Refill, 0, "b", 30),
+            entry(LedgerKind::Withdrawal, 2, "a", 70),
+        ];
+
+        let summary = Stats::from_entries(entries.iter());
+        assert_eq!(summary.totals.withdrawals, 3);
+        assert_eq!(summary.totals.units_withdrawn, 6);
+        assert_eq!(summary.totals.rejections, 1);
+        assert_eq!(summary.totals.refills, 1);
+        assert_eq!(summary.rejection_rate, 0.25);
+        assert_eq!(summary.clients["a"].units_withdrawn, 5);
+        assert_eq!(summary.clients["b"].refills, 1);
+        assert_eq!(summary.per_minute.len(), 2);
+        assert_eq!(summary.per_minute[1].counts.withdrawals, 1);
+    }
+}
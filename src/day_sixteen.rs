@@ -1,36 +1,89 @@
-use std::{cell::LazyCell, collections::HashMap, ops::Deref, sync::Mutex};
+mod keys;
+
+use std::{
+    cell::LazyCell,
+    collections::HashMap,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        LazyLock, Mutex, RwLock,
+    },
+};
 
 use actix_web::{
     cookie::Cookie,
-    get, post,
+    get,
+    http::header,
+    post,
     web::{Data, Json},
     HttpRequest, HttpResponse, Responder,
 };
-use jsonwebtoken::{encode, Header, Validation};
-use serde::{Deserialize, Serialize};
+use chrono::Utc;
+use jsonwebtoken::Validation;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::Value;
 use uuid::Uuid;
 
+pub use keys::KeySource;
+use keys::{KeyRing, KeyRingView};
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Claims {
     id: Uuid,
 }
 
-const SECRET: &[u8] = b"super secure secret not to be used in production :P";
-const ENCODE_KEY: LazyCell<jsonwebtoken::EncodingKey> =
-    LazyCell::new(|| jsonwebtoken::EncodingKey::from_secret(SECRET));
-const DECODE_KEY: LazyCell<jsonwebtoken::DecodingKey> =
-    LazyCell::new(|| jsonwebtoken::DecodingKey::from_secret(SECRET));
+/// The keys gifts and player tokens are signed with, as last set up by [`load_keys`].
+static KEYS: LazyLock<RwLock<KeyRing>> = LazyLock::new(|| RwLock::new(KeyRing::fallback()));
+/// Whether [`KEYS`] came from the secrets rather than [`KeyRing::fallback`].
+static CONFIGURED: AtomicBool = AtomicBool::new(false);
+/// Where [`KEYS`] were last loaded from, for `/16/keys/reload` to read again.
+static SOURCE: Mutex<Option<KeySource>> = Mutex::new(None);
 const SANTA_KEY: LazyCell<jsonwebtoken::DecodingKey> = LazyCell::new(|| {
     jsonwebtoken::DecodingKey::from_rsa_pem(include_bytes!("../day16_santa_public_key.pem"))
         .unwrap()
 });
 
+/// Loads the keys from `source`, see [`KeySource`]. Without one, the keys in use stay, which is
+/// only the built-in key if `development` allows it.
+pub fn load_keys(source: Option<KeySource>, development: bool) -> Result<KeyRingView, String> {
+    let mut keys = KEYS.write().unwrap();
+    match source {
+        Some(source) => {
+            *keys = KeyRing::new(source.read()?)?;
+            CONFIGURED.store(true, Ordering::Relaxed);
+            *SOURCE.lock().unwrap() = Some(source);
+            println!("Loaded gift keys");
+        }
+        None if development || CONFIGURED.load(Ordering::Relaxed) => {
+            println!("No GIFT_KEY_FILE or GIFT_KEYS, keeping the gift keys in use")
+        }
+        None => {
+            return Err(
+                "No GIFT_KEY_FILE or GIFT_KEYS, and the built-in gift key is only for \
+                        development"
+                    .to_string(),
+            )
+        }
+    }
+    Ok(keys.view(Utc::now()))
+}
+
+/// Signs `claims` with the current key, the way `/16/wrap` signs gifts.
+pub(crate) fn sign(claims: &impl Serialize) -> String {
+    KEYS.read().unwrap().sign(claims)
+}
+
+/// The claims of a token made by [`sign`], if it's ours, its key is still accepted, it's for
+/// `aud` and it hasn't expired. Tokens without an `exp` claim never do, and gifts have no `aud`.
+pub(crate) fn verify<T: DeserializeOwned>(jwt: &str, aud: Option<&str>) -> Option<T> {
+    KEYS.read().unwrap().verify(jwt, aud, Utc::now())
+}
+
 #[post("/16/wrap")]
 pub async fn wrap(data: Json<Value>, store: Data<GiftStore>) -> impl Responder {
     let id = Uuid::new_v4();
     let claims = Claims { id };
-    let jwt = encode(&Header::default(), &claims, &ENCODE_KEY).unwrap();
+    let jwt = sign(&claims);
     store.lock().unwrap().insert(id, data.into_inner());
 
     HttpResponse::Ok().cookie(Cookie::new("gift", jwt)).finish()
@@ -42,22 +95,13 @@ pub async fn unwrap(req: HttpRequest, store: Data<GiftStore>) -> impl Responder
         println!("No cookie found");
         return HttpResponse::BadRequest().finish();
     };
-    let jwt = cookie.value();
-    let Ok(headers) = jsonwebtoken::decode_header(&jwt) else {
-        println!("Failed to decode JWT header");
-        return HttpResponse::BadRequest().finish();
-    };
-    println!("Headers {headers:?}");
-    let mut validation = Validation::new(jsonwebtoken::Algorithm::HS256);
-    validation.required_spec_claims.clear();
-
-    let Ok(claims) = jsonwebtoken::decode::<Claims>(&jwt, &DECODE_KEY, &validation) else {
+    let Some(claims) = verify::<Claims>(cookie.value(), None) else {
         println!("Failed to decode JWT");
         return HttpResponse::BadRequest().finish();
     };
 
     let store = store.lock().unwrap();
-    let Some(data) = store.get(&claims.claims.id) else {
+    let Some(data) = store.get(&claims.id) else {
         println!("No data found");
         return HttpResponse::BadRequest().finish();
     };
@@ -65,6 +109,58 @@ pub async fn unwrap(req: HttpRequest, store: Data<GiftStore>) -> impl Responder
     HttpResponse::Ok().body(data.to_string())
 }
 
+/// Reads the `GIFT_KEY_FILE` again, for rotating keys without a restart: put the new key first
+/// and give the old one a `retired_at`, and it keeps verifying for the grace period after that.
+/// Keys from `GIFT_KEYS` only change with a redeploy, so there's nothing to reload. Only callers
+/// with the `GIFT_ADMIN_TOKEN` as their bearer token may.
+#[post("/16/keys/reload")]
+pub async fn reload_keys(req: HttpRequest) -> impl Responder {
+    if !is_admin(&req) {
+        return HttpResponse::Forbidden().finish();
+    }
+
+    let path = match &*SOURCE.lock().unwrap() {
+        Some(KeySource::File(path)) => path.clone(),
+        Some(KeySource::Inline(_)) => {
+            return HttpResponse::Conflict()
+                .body("GIFT_KEYS only change with a redeploy, set GIFT_KEY_FILE to rotate keys")
+        }
+        None => return HttpResponse::Conflict().body("No GIFT_KEY_FILE to reload"),
+    };
+    match load_keys(Some(KeySource::File(path)), false) {
+        Ok(view) => HttpResponse::Ok().json(view),
+        Err(e) => {
+            println!("Failed to reload gift keys: {e}");
+            HttpResponse::InternalServerError().body(e)
+        }
+    }
+}
+
+/// Whether `req` has the `GIFT_ADMIN_TOKEN` as its bearer token. Without one set, nobody does.
+pub(crate) fn is_admin(req: &HttpRequest) -> bool {
+    let admin = std::env::var("GIFT_ADMIN_TOKEN")
+        .ok()
+        .filter(|token| !token.is_empty());
+    let bearer = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match (admin, bearer) {
+        (Some(admin), Some(bearer)) => same_secret(&admin, bearer),
+        _ => false,
+    }
+}
+
+/// Compares two secrets in time that doesn't depend on where they differ.
+fn same_secret(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
 #[post("/16/decode")]
 pub async fn decode(jwt: String) -> impl Responder {
     let Ok(headers) = jsonwebtoken::decode_header(&jwt) else {
@@ -0,0 +1,311 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use shuttle_runtime::SecretStore;
+
+/// The secret tokens were signed with before keys could be configured. It's only used when
+/// no keys are, so cookies handed out by older servers keep working in development.
+const FALLBACK_SECRET: &[u8] = b"super secure secret not to be used in production :P";
+
+/// The keys that sign and verify the server's tokens.
+///
+/// The first key that isn't retired signs new tokens, with its `kid` in the header. Retired
+/// keys keep verifying the tokens they signed until their grace period is up, so a key can be
+/// rotated out without logging everyone out at once.
+pub struct KeyRing {
+    keys: Vec<Key>,
+    grace_period: Duration,
+}
+
+struct Key {
+    kid: String,
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    /// When the key stopped signing tokens.
+    retired_at: Option<DateTime<Utc>>,
+}
+
+/// Where the keys are read from, as set in `Secrets.toml`.
+pub enum KeySource {
+    /// `GIFT_KEY_FILE`, a path to the keys. It's read again on every reload, so keys can be
+    /// rotated by changing the file.
+    File(String),
+    /// `GIFT_KEYS`, the keys themselves. Secrets only change with a redeploy, so these do too.
+    Inline(String),
+}
+
+/// The keys as written in `GIFT_KEYS` or the `GIFT_KEY_FILE`, in TOML:
+///
+/// ```toml
+/// grace_period = 604800
+///
+/// [[keys]]
+/// kid = "2024-12"
+/// secret = "..."
+///
+/// [[keys]]
+/// kid = "2024-11"
+/// secret = "..."
+/// retired_at = "2024-12-01T00:00:00Z"
+/// ```
+#[derive(Deserialize)]
+pub struct KeyConfig {
+    /// Seconds a retired key keeps verifying tokens, a week unless given.
+    #[serde(default = "KeyConfig::default_grace_period")]
+    grace_period: u64,
+    keys: Vec<KeyEntry>,
+}
+
+#[derive(Deserialize)]
+struct KeyEntry {
+    kid: String,
+    secret: String,
+    /// RFC 3339. Every key but the one signing has to say, so the grace period runs from the
+    /// same time whenever and wherever the keys are loaded.
+    retired_at: Option<String>,
+}
+
+/// What `/16/keys/reload` tells about the keys, leaving out the secrets.
+#[derive(Serialize)]
+pub struct KeyRingView {
+    signing: String,
+    /// Retired keys still verifying tokens, and until when.
+    retired: Vec<RetiredKey>,
+}
+
+#[derive(Serialize)]
+pub struct RetiredKey {
+    kid: String,
+    accepted_until: DateTime<Utc>,
+}
+
+impl KeyConfig {
+    fn default_grace_period() -> u64 {
+        7 * 24 * 60 * 60
+    }
+}
+
+impl KeySource {
+    /// `GIFT_KEY_FILE`, or else `GIFT_KEYS`, or `None` if neither is set.
+    pub fn from_secrets(secrets: &SecretStore) -> Option<Self> {
+        secrets
+            .get("GIFT_KEY_FILE")
+            .map(Self::File)
+            .or_else(|| secrets.get("GIFT_KEYS").map(Self::Inline))
+    }
+
+    pub fn read(&self) -> Result<KeyConfig, String> {
+        let toml = match self {
+            Self::File(path) => {
+                std::fs::read_to_string(path).map_err(|e| format!("Can't read {path}: {e}"))?
+            }
+            Self::Inline(toml) => toml.clone(),
+        };
+        toml::from_str(&toml).map_err(|e| format!("Bad gift keys: {e}"))
+    }
+}
+
+impl KeyRing {
+    /// A single key with the old built-in secret.
+    pub fn fallback() -> Self {
+        Self {
+            keys: vec![Key::new("default".to_string(), FALLBACK_SECRET, None)],
+            grace_period: Duration::ZERO,
+        }
+    }
+
+    /// The keys in `config`, the one without a `retired_at` signing.
+    pub fn new(config: KeyConfig) -> Result<Self, String> {
+        let mut keys = vec![];
+        let mut signing = None;
+        for entry in config.keys {
+            if entry.secret.is_empty() {
+                return Err(format!("Key {} has no secret", entry.kid));
+            }
+            if keys.iter().any(|key: &Key| key.kid == entry.kid) {
+                return Err(format!("Key {} is there twice", entry.kid));
+            }
+            let retired_at = match entry.retired_at {
+                Some(at) => Some(
+                    DateTime::parse_from_rfc3339(&at)
+                        .map_err(|e| format!("Key {} has a bad retired_at: {e}", entry.kid))?
+                        .to_utc(),
+                ),
+                None => match &signing {
+                    None => {
+                        signing = Some(entry.kid.clone());
+                        None
+                    }
+                    Some(signing) => {
+                        return Err(format!(
+                            "Key {} needs a retired_at, only {signing} can sign",
+                            entry.kid
+                        ))
+                    }
+                },
+            };
+            keys.push(Key::new(entry.kid, entry.secret.as_bytes(), retired_at));
+        }
+        let Some(signing) = signing else {
+            return Err("There's no key that isn't retired to sign with".to_string());
+        };
+
+        // The signing key goes first, so it's the one tokens without a kid are tried on first
+        keys.sort_by_key(|key| key.kid != signing);
+        Ok(Self {
+            keys,
+            grace_period: Duration::from_secs(config.grace_period),
+        })
+    }
+
+    fn signing_key(&self) -> &Key {
+        self.keys
+            .iter()
+            .find(|key| key.retired_at.is_none())
+            .expect("a key ring always has a signing key")
+    }
+
+    /// When a key stops verifying tokens, if it's been retired.
+    fn accepted_until(&self, key: &Key) -> Option<DateTime<Utc>> {
+        key.retired_at.map(|at| at + self.grace_period)
+    }
+
+    fn accepts(&self, key: &Key, now: DateTime<Utc>) -> bool {
+        self.accepted_until(key).is_none_or(|until| now < until)
+    }
+
+    pub fn sign(&self, claims: &impl Serialize) -> String {
+        let key = self.signing_key();
+        let header = Header {
+            kid: Some(key.kid.clone()),
+            ..Header::default()
+        };
+        jsonwebtoken::encode(&header, claims, &key.encoding).unwrap()
+    }
+
+    /// The claims of a token signed by one of the keys that's still accepted at `now`, and
+    /// meant for `aud`. Without one, only tokens that don't name an audience are. Tokens from
+    /// before keys had ids are tried against all of them.
+    pub fn verify<T: DeserializeOwned>(
+        &self,
+        jwt: &str,
+        aud: Option<&str>,
+        now: DateTime<Utc>,
+    ) -> Option<T> {
+        let header = jsonwebtoken::decode_header(jwt).ok()?;
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.required_spec_claims.clear();
+        if let Some(aud) = aud {
+            validation.set_audience(&[aud]);
+            validation.set_required_spec_claims(&["aud"]);
+        }
+
+        let mut keys = self.keys.iter().filter(|key| self.accepts(key, now));
+        match header.kid {
+            Some(kid) => keys
+                .find(|key| key.kid == kid)
+                .and_then(|key| jsonwebtoken::decode(jwt, &key.decoding, &validation).ok()),
+            None => keys.find_map(|key| jsonwebtoken::decode(jwt, &key.decoding, &validation).ok()),
+        }
+        .map(|token| token.claims)
+    }
+
+    pub fn view(&self, now: DateTime<Utc>) -> KeyRingView {
+        KeyRingView {
+            signing: self.signing_key().kid.clone(),
+            retired: self
+                .keys
+                .iter()
+                .filter(|key| self.accepts(key, now))
+                .filter_map(|key| {
+                    Some(RetiredKey {
+                        kid: key.kid.clone(),
+                        accepted_until: self.accepted_until(key)?,
+                    })
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Key {
+    fn new(kid: String, secret: &[u8], retired_at: Option<DateTime<Utc>>) -> Self {
+        Self {
+            kid,
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret),
+            retired_at,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::TimeDelta;
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::day_sixteen::Claims;
+
+    fn ring(toml: &str) -> Result<KeyRing, String> {
+        KeyRing::new(toml::from_str(toml).unwrap())
+    }
+
+    #[test]
+    fn test_key_rotation() {
+        let start = "2024-12-01T00:00:00Z".parse().unwrap();
+        let old = ring(
+            r#"
+            keys = [{ kid = "old", secret = "one" }]
+            "#,
+        )
+        .unwrap();
+        let claims = Claims { id: Uuid::new_v4() };
+        let jwt = old.sign(&claims);
+        assert!(old.verify::<Claims>(&jwt, None, start).is_some());
+
+        // The new key signs, and the old one verifies what it signed for a day after it retired
+        let later = start + TimeDelta::hours(1);
+        let new = ring(
+            r#"
+            grace_period = 86400
+            keys = [
+                { kid = "new", secret = "two" },
+                { kid = "old", secret = "one", retired_at = "2024-12-01T00:30:00Z" },
+            ]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            jsonwebtoken::decode_header(&new.sign(&claims))
+                .unwrap()
+                .kid
+                .unwrap(),
+            "new"
+        );
+        assert!(new.verify::<Claims>(&jwt, None, later).is_some());
+        let expired = start + TimeDelta::days(1) + TimeDelta::hours(1);
+        assert!(new.verify::<Claims>(&jwt, None, expired).is_none());
+
+        // Tokens from before keys had ids are tried against every key
+        let unnamed = jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &claims,
+            &jsonwebtoken::EncodingKey::from_secret(b"one"),
+        )
+        .unwrap();
+        assert!(new.verify::<Claims>(&unnamed, None, later).is_some());
+
+        // One key has to sign, and only one
+        assert!(ring(
+            r#"keys = [{ kid = "old", secret = "one", retired_at = "2024-12-01T00:00:00Z" }]"#
+        )
+        .is_err());
+        assert!(ring(
+            r#"keys = [{ kid = "new", secret = "two" }, { kid = "old", secret = "one" }]"#
+        )
+        .is_err());
+    }
+}
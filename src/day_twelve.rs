@@ -1,52 +1,1056 @@
-use std::{fmt::Display, sync::Mutex};
+mod bitboard;
+mod clock;
+mod games;
+mod live;
+mod lobby;
+mod notation;
+mod picture;
+mod search;
+mod tournament;
+mod ui;
+
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    ops::Range,
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
 
 use actix_web::{
-    get, post,
-    web::{Data, Path},
-    HttpResponse, Responder,
+    get,
+    http::{
+        header::{Accept, Header},
+        StatusCode,
+    },
+    post,
+    web::{self, Data, Path, Query},
+    HttpRequest, HttpResponse, HttpResponseBuilder, Responder,
 };
 use rand::Rng;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use bitboard::Bitboard;
+pub use clock::watch_clocks;
+use clock::{Clock, ClockView, TimeControl, Timeout};
+pub use games::{BoardData, Game};
+pub use live::{game_stream, game_ws, stream, ws};
+pub use lobby::{join_lobby, lobby_ticket, Lobby};
+pub use picture::{board_png, board_svg, game_board_png, game_board_svg};
+use search::{Limits, Position};
+pub use tournament::{add_bot, leaderboard, start_tournament, Tournament};
+pub use ui::{
+    game_ui_board, game_ui_page, game_ui_place, game_ui_place_at, game_ui_pop, game_ui_reset,
+    ui_board, ui_page, ui_place, ui_place_at, ui_pop, ui_reset,
+};
 
 #[get("/12/board")]
-pub async fn board(board_data: Data<BoardData>) -> impl Responder {
-    let board = board_data.lock().unwrap();
-    HttpResponse::Ok().body(board.to_string())
+pub async fn board(board_data: Data<BoardData>, req: HttpRequest) -> impl Responder {
+    show(&board_data, BoardData::DEFAULT_GAME, wants_json(&req))
 }
 
 #[post("/12/place/{team}/{column}")]
-pub async fn place(path: Path<(Team, u8)>, board_data: Data<BoardData>) -> impl Responder {
-    let mut b = board_data.lock().unwrap();
-    if b.winner() != State::Incomplete {
-        return HttpResponse::ServiceUnavailable().body(b.to_string());
-    }
+pub async fn place(
+    path: Path<(Team, u8)>,
+    board_data: Data<BoardData>,
+    req: HttpRequest,
+) -> impl Responder {
+    let (team, column) = path.into_inner();
+    place_in(
+        &board_data,
+        BoardData::DEFAULT_GAME,
+        team,
+        column,
+        Action::Drop,
+        wants_json(&req),
+    )
+    .await
+}
 
+#[post("/12/place/{team}/{column}/{row}")]
+pub async fn place_at(
+    path: Path<(Team, u8, u8)>,
+    board_data: Data<BoardData>,
+    req: HttpRequest,
+) -> impl Responder {
+    let (team, column, row) = path.into_inner();
+    place_in(
+        &board_data,
+        BoardData::DEFAULT_GAME,
+        team,
+        column,
+        Action::Put { row },
+        wants_json(&req),
+    )
+    .await
+}
+
+#[post("/12/pop/{team}/{column}")]
+pub async fn pop(
+    path: Path<(Team, u8)>,
+    board_data: Data<BoardData>,
+    req: HttpRequest,
+) -> impl Responder {
     let (team, column) = path.into_inner();
-    if !(1..=4).contains(&column) {
-        return HttpResponse::BadRequest().body(b.to_string());
+    place_in(
+        &board_data,
+        BoardData::DEFAULT_GAME,
+        team,
+        column,
+        Action::Pop,
+        wants_json(&req),
+    )
+    .await
+}
+
+#[post("/12/reset")]
+pub async fn reset(
+    board_data: Data<BoardData>,
+    options: Query<GameOptions>,
+    req: HttpRequest,
+) -> impl Responder {
+    reset_in(&board_data, BoardData::DEFAULT_GAME, Some(&options), &req).await
+}
+
+#[get("/12/random-board")]
+pub async fn random_board(
+    board_data: Data<BoardData>,
+    seed: Query<Seed>,
+    req: HttpRequest,
+) -> impl Responder {
+    random_in(
+        &board_data,
+        BoardData::DEFAULT_GAME,
+        seed.seed,
+        wants_json(&req),
+    )
+    .await
+}
+
+#[get("/12/random-game")]
+pub async fn random_game(
+    board_data: Data<BoardData>,
+    seed: Query<Seed>,
+    req: HttpRequest,
+) -> impl Responder {
+    random_game_in(
+        &board_data,
+        BoardData::DEFAULT_GAME,
+        seed.seed,
+        wants_json(&req),
+    )
+    .await
+}
+
+#[post("/12/undo")]
+pub async fn undo(board_data: Data<BoardData>, req: HttpRequest) -> impl Responder {
+    undo_in(&board_data, BoardData::DEFAULT_GAME, None, wants_json(&req)).await
+}
+
+#[get("/12/suggest/{team}")]
+pub async fn suggest(
+    team: Path<Team>,
+    limits: Query<Limits>,
+    board_data: Data<BoardData>,
+) -> impl Responder {
+    suggest_in(
+        &board_data,
+        BoardData::DEFAULT_GAME,
+        team.into_inner(),
+        &limits,
+    )
+    .await
+}
+
+#[get("/12/analysis")]
+pub async fn analysis(limits: Query<Limits>, board_data: Data<BoardData>) -> impl Responder {
+    analysis_in(&board_data, BoardData::DEFAULT_GAME, &limits).await
+}
+
+#[get("/12/perft")]
+pub async fn perft(depth: Query<Depth>, board_data: Data<BoardData>) -> impl Responder {
+    perft_in(&board_data, BoardData::DEFAULT_GAME, depth.depth).await
+}
+
+#[post("/12/games")]
+pub async fn create_game(
+    board_data: Data<BoardData>,
+    options: Query<GameOptions>,
+) -> impl Responder {
+    let new_board = match options.build() {
+        Ok(new_board) => new_board,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    let seed = new_board.seed;
+    let id = board_data.create(new_board, options.opponent).await;
+    HttpResponse::Created()
+        .append_header(("Location", format!("/12/games/{id}/board")))
+        .json(serde_json::json!({ "id": id, "seed": seed }))
+}
+
+#[get("/12/games/{id}/board")]
+pub async fn game_board(
+    id: Path<Uuid>,
+    board_data: Data<BoardData>,
+    req: HttpRequest,
+) -> impl Responder {
+    show(&board_data, id.into_inner(), wants_json(&req))
+}
+
+#[post("/12/games/{id}/place/{team}/{column}")]
+pub async fn game_place(
+    path: Path<(Uuid, Team, u8)>,
+    board_data: Data<BoardData>,
+    req: HttpRequest,
+) -> impl Responder {
+    let (id, team, column) = path.into_inner();
+    if let Some(res) = seat_check(&board_data, id, &req, Some(&team)) {
+        return res;
     }
+    place_in(
+        &board_data,
+        id,
+        team,
+        column,
+        Action::Drop,
+        wants_json(&req),
+    )
+    .await
+}
+
+#[post("/12/games/{id}/place/{team}/{column}/{row}")]
+pub async fn game_place_at(
+    path: Path<(Uuid, Team, u8, u8)>,
+    board_data: Data<BoardData>,
+    req: HttpRequest,
+) -> impl Responder {
+    let (id, team, column, row) = path.into_inner();
+    let action = Action::Put { row };
+    if let Some(res) = seat_check(&board_data, id, &req, Some(&team)) {
+        return res;
+    }
+    place_in(&board_data, id, team, column, action, wants_json(&req)).await
+}
 
-    let res = b.place(team, column);
+#[post("/12/games/{id}/pop/{team}/{column}")]
+pub async fn game_pop(
+    path: Path<(Uuid, Team, u8)>,
+    board_data: Data<BoardData>,
+    req: HttpRequest,
+) -> impl Responder {
+    let (id, team, column) = path.into_inner();
+    if let Some(res) = seat_check(&board_data, id, &req, Some(&team)) {
+        return res;
+    }
+    place_in(&board_data, id, team, column, Action::Pop, wants_json(&req)).await
+}
 
-    if let Err(()) = res {
-        return HttpResponse::ServiceUnavailable().body(b.to_string());
+#[post("/12/games/{id}/reset")]
+pub async fn game_reset(
+    id: Path<Uuid>,
+    board_data: Data<BoardData>,
+    options: Query<GameOptions>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Some(res) = seat_check(&board_data, *id, &req, None) {
+        return res;
     }
+    // A lobby game can't be set up again, so it only takes a reset without options
+    let options = (!req.query_string().is_empty()).then_some(&*options);
+    reset_in(&board_data, id.into_inner(), options, &req).await
+}
 
-    HttpResponse::Ok().body(b.to_string())
+#[get("/12/games/{id}/random-board")]
+pub async fn game_random_board(
+    id: Path<Uuid>,
+    board_data: Data<BoardData>,
+    seed: Query<Seed>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Some(res) = lobby_check(&board_data, *id, &req) {
+        return res;
+    }
+    random_in(&board_data, id.into_inner(), seed.seed, wants_json(&req)).await
 }
 
-#[post("/12/reset")]
-pub async fn reset(board_data: Data<BoardData>) -> impl Responder {
-    let mut b = board_data.lock().unwrap();
-    *b = Board::new();
-    HttpResponse::Ok().body(b.to_string())
+#[get("/12/games/{id}/random-game")]
+pub async fn game_random_game(
+    id: Path<Uuid>,
+    board_data: Data<BoardData>,
+    seed: Query<Seed>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Some(res) = lobby_check(&board_data, *id, &req) {
+        return res;
+    }
+    random_game_in(&board_data, id.into_inner(), seed.seed, wants_json(&req)).await
 }
 
-#[get("/12/random-board")]
-pub async fn random_board(board_data: Data<BoardData>) -> impl Responder {
-    let mut b = board_data.lock().unwrap();
-    b.random();
-    HttpResponse::Ok().body(b.to_string())
+#[post("/12/games/{id}/undo")]
+pub async fn game_undo(
+    id: Path<Uuid>,
+    board_data: Data<BoardData>,
+    req: HttpRequest,
+) -> impl Responder {
+    let seat = lobby::player(*id, &req);
+    undo_in(
+        &board_data,
+        id.into_inner(),
+        seat.as_ref(),
+        wants_json(&req),
+    )
+    .await
+}
+
+#[get("/12/games/{id}/suggest/{team}")]
+pub async fn game_suggest(
+    path: Path<(Uuid, Team)>,
+    limits: Query<Limits>,
+    board_data: Data<BoardData>,
+) -> impl Responder {
+    let (id, team) = path.into_inner();
+    suggest_in(&board_data, id, team, &limits).await
+}
+
+#[get("/12/games/{id}/analysis")]
+pub async fn game_analysis(
+    id: Path<Uuid>,
+    limits: Query<Limits>,
+    board_data: Data<BoardData>,
+) -> impl Responder {
+    analysis_in(&board_data, id.into_inner(), &limits).await
+}
+
+#[get("/12/games/{id}/perft")]
+pub async fn game_perft(
+    id: Path<Uuid>,
+    depth: Query<Depth>,
+    board_data: Data<BoardData>,
+) -> impl Responder {
+    perft_in(&board_data, id.into_inner(), depth.depth).await
+}
+
+#[get("/12/export")]
+pub async fn export(board_data: Data<BoardData>) -> impl Responder {
+    export_in(&board_data, BoardData::DEFAULT_GAME)
+}
+
+#[post("/12/import")]
+pub async fn import(body: String, board_data: Data<BoardData>, req: HttpRequest) -> impl Responder {
+    import_in(
+        &board_data,
+        BoardData::DEFAULT_GAME,
+        &body,
+        wants_json(&req),
+    )
+    .await
+}
+
+#[get("/12/games/{id}/export")]
+pub async fn game_export(id: Path<Uuid>, board_data: Data<BoardData>) -> impl Responder {
+    export_in(&board_data, id.into_inner())
+}
+
+#[post("/12/games/{id}/import")]
+pub async fn game_import(
+    id: Path<Uuid>,
+    body: String,
+    board_data: Data<BoardData>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Some(res) = lobby_check(&board_data, *id, &req) {
+        return res;
+    }
+    import_in(&board_data, id.into_inner(), &body, wants_json(&req)).await
+}
+
+#[get("/12/games/{id}/moves")]
+pub async fn game_moves(id: Path<Uuid>, board_data: Data<BoardData>) -> impl Responder {
+    match board_data.moves(id.into_inner()).await {
+        Ok(Some(moves)) => HttpResponse::Ok().json(moves),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            println!("Failed to fetch moves: {e}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Replay {
+    upto: Option<usize>,
+}
+
+#[get("/12/games/{id}/replay")]
+pub async fn game_replay(
+    id: Path<Uuid>,
+    replay: Query<Replay>,
+    board_data: Data<BoardData>,
+    req: HttpRequest,
+) -> impl Responder {
+    match board_data.stored_board(id.into_inner(), replay.upto).await {
+        Ok(Some(b)) => render(HttpResponse::Ok(), &b, wants_json(&req)),
+        Ok(None) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            println!("Failed to replay game: {e}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Whether the client would rather have a [`BoardView`] than the emoji board.
+fn wants_json(req: &HttpRequest) -> bool {
+    Accept::parse(req).is_ok_and(|accept| accept.preference().essence_str() == "application/json")
+}
+
+/// Says where a random board came from: the seed, and how many fills or games the generator
+/// had made from it, counting this one. Reseeding with the seed and asking as many times again
+/// gives the same board.
+fn with_seed(mut res: HttpResponseBuilder, b: &Board) -> HttpResponseBuilder {
+    res.append_header(("X-Seed", b.seed.to_string()))
+        .append_header(("X-Seed-Round", b.rounds.to_string()));
+    res
+}
+
+fn render(mut res: HttpResponseBuilder, b: &Board, json: bool) -> HttpResponse {
+    if json {
+        res.json(BoardView::new(b))
+    } else {
+        res.body(b.to_string())
+    }
+}
+
+fn reject(e: &MoveError, b: &Board, json: bool) -> HttpResponse {
+    let mut res = HttpResponse::build(e.status());
+    if json {
+        return res.json(serde_json::json!({
+            "error": e.code(),
+            "reason": e.to_string(),
+            "board": BoardView::new(b),
+        }));
+    }
+    match e {
+        // The original routes answered these with nothing but the board
+        MoveError::GameOver | MoveError::OutOfRange | MoveError::ColumnFull
+            if b.variant == Variant::Classic =>
+        {
+            res.body(b.to_string())
+        }
+        _ => res.body(format!("{e}\n{b}")),
+    }
+}
+
+// The handlers below are shared by the default game and the `/12/games/{id}/...` routes
+
+/// Turns away changes to a lobby game from anyone not seated there, as in
+/// [`lobby::check_seat`].
+fn seat_check(
+    board_data: &BoardData,
+    id: Uuid,
+    req: &HttpRequest,
+    team: Option<&Team>,
+) -> Option<HttpResponse> {
+    let game = board_data.game(id)?;
+    let e = lobby::check_seat(&game, lobby::player(id, req).as_ref(), team).err()?;
+    let b = game.board.lock().unwrap();
+    Some(reject(&e, &b, wants_json(req)))
+}
+
+/// Turns away changes that would set a lobby game up again, which nobody gets to make.
+fn lobby_check(board_data: &BoardData, id: Uuid, req: &HttpRequest) -> Option<HttpResponse> {
+    let game = board_data.game(id)?;
+    game.seated.as_ref()?;
+    let b = game.board.lock().unwrap();
+    Some(reject(&MoveError::LobbyGame, &b, wants_json(req)))
+}
+
+fn show(board_data: &BoardData, id: Uuid, json: bool) -> HttpResponse {
+    let Some(game) = board_data.game(id) else {
+        return HttpResponse::NotFound().finish();
+    };
+    let b = game.board.lock().unwrap();
+    render(HttpResponse::Ok(), &b, json)
+}
+
+async fn place_in(
+    board_data: &BoardData,
+    id: Uuid,
+    team: Team,
+    column: u8,
+    action: Action,
+    json: bool,
+) -> HttpResponse {
+    let Some(game) = board_data.game(id) else {
+        return HttpResponse::NotFound().finish();
+    };
+    let _saving = game.saving.lock().await;
+
+    let res = make_move(board_data, id, &game, team.clone(), column, action, json).await;
+    if let Err(res) = res {
+        return res;
+    }
+
+    if game.auto_reply.load(Ordering::Relaxed) {
+        let position = {
+            let b = game.board.lock().unwrap();
+            (b.winner() == State::Incomplete && b.variant.can_reply()).then(|| Position::new(&b))
+        };
+        if let Some(position) = position {
+            let reply_team = team.other();
+            let reply = web::block(move || position.best_move(&reply_team, &Limits::default()))
+                .await
+                .ok()
+                .flatten();
+            if let Some(column) = reply {
+                // The search only picks legal columns, so this can't fail for the player's sake
+                let reply_team = team.other();
+                let _ = make_move(
+                    board_data,
+                    id,
+                    &game,
+                    reply_team,
+                    column,
+                    Action::Drop,
+                    json,
+                )
+                .await;
+            }
+        }
+    }
+
+    let b = game.board.lock().unwrap();
+    render(HttpResponse::Ok(), &b, json)
+}
+
+/// Plays one move and saves it, or gives back the response explaining why it couldn't be made.
+/// The caller must hold `game.saving`.
+async fn make_move(
+    board_data: &BoardData,
+    id: Uuid,
+    game: &Game,
+    team: Team,
+    column: u8,
+    action: Action,
+    json: bool,
+) -> Result<(), HttpResponse> {
+    let (timeouts, played) = {
+        let mut b = game.board.lock().unwrap();
+        // The move can find a team out of time that the background task hasn't got to yet
+        let before = b.timeouts().len();
+        let played = b.play(team, column, action);
+        let timeouts = b.timeouts()[before..].to_vec();
+        if !timeouts.is_empty() {
+            board_data.publish(id, || live::update_message(id, "timeout", &b));
+        }
+        let played = match played {
+            Ok(()) => {
+                board_data.publish(id, || live::update_message(id, "place", &b));
+                let m = b.history.last().cloned().expect("a move was just made");
+                Ok((b.history.len(), m))
+            }
+            Err(e) => Err(reject(&e, &b, json)),
+        };
+        (timeouts, played)
+    };
+
+    for timeout in &timeouts {
+        if let Err(e) = board_data.save_timeout(id, timeout).await {
+            println!("Failed to save timeout in game {id}: {e}");
+        }
+    }
+    let (seq, m) = played?;
+    if let Err(e) = board_data.save_move(id, seq, &m).await {
+        println!("Failed to save move {seq} of game {id}: {e}");
+    }
+    Ok(())
+}
+
+/// Takes back the last move. In a lobby game only the player who made it can, from `seat`.
+async fn undo_in(
+    board_data: &BoardData,
+    id: Uuid,
+    seat: Option<&Team>,
+    json: bool,
+) -> HttpResponse {
+    let Some(game) = board_data.game(id) else {
+        return HttpResponse::NotFound().finish();
+    };
+    let _saving = game.saving.lock().await;
+
+    let (seq, res) = {
+        let mut b = game.board.lock().unwrap();
+        let last = b.history.last().map(|m| &m.team);
+        if let Err(e) = lobby::check_seat(&game, seat, last) {
+            return reject(&e, &b, json);
+        }
+        let seq = b.history.len();
+        if b.undo().is_none() {
+            return reject(&MoveError::NothingToUndo, &b, json);
+        }
+        board_data.publish(id, || live::update_message(id, "undo", &b));
+        (seq, render(HttpResponse::Ok(), &b, json))
+    };
+
+    if let Err(e) = board_data.delete_move(id, seq).await {
+        println!("Failed to delete move {seq} of game {id}: {e}");
+    }
+    res
+}
+
+async fn suggest_in(board_data: &BoardData, id: Uuid, team: Team, limits: &Limits) -> HttpResponse {
+    let Some(game) = board_data.game(id) else {
+        return HttpResponse::NotFound().finish();
+    };
+    let position = {
+        let b = game.board.lock().unwrap();
+        if !b.variant.searchable() {
+            let reason = format!("No suggestions for {} games\n", b.variant.as_str());
+            return HttpResponse::BadRequest().body(reason);
+        }
+        Position::new(&b)
+    };
+    let limits = limits.clamped();
+
+    match web::block(move || position.best_move(&team, &limits)).await {
+        Ok(Some(column)) => HttpResponse::Ok().json(serde_json::json!({ "column": column })),
+        // A classic game only runs out of drops once it's over
+        Ok(None) => HttpResponse::ServiceUnavailable().body(format!("{}\n", MoveError::GameOver)),
+        Err(e) => {
+            println!("Search failed: {e}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// The threats on the board: for every team, the moves that win at once, the moves it has to
+/// block before another team wins, and whether the search sees a win it can force.
+async fn analysis_in(board_data: &BoardData, id: Uuid, limits: &Limits) -> HttpResponse {
+    let Some(game) = board_data.game(id) else {
+        return HttpResponse::NotFound().finish();
+    };
+    let (state, next, mut hints, positions) = {
+        let b = game.board.lock().unwrap();
+        let teams = b.variant.teams();
+        let wins: Vec<Vec<Hint>> = teams
+            .iter()
+            .map(|team| b.winning_moves(team).into_iter().map(Hint::from).collect())
+            .collect();
+        let hints: Vec<TeamHints> = teams
+            .iter()
+            .enumerate()
+            .map(|(i, team)| TeamHints {
+                team: team.clone(),
+                // Pieces can block a drop or a put, but not a pop
+                blocks: (0..teams.len())
+                    .filter(|&j| j != i)
+                    .flat_map(|j| wins[j].iter().filter(|hint| hint.action != Action::Pop))
+                    .cloned()
+                    .collect(),
+                wins: wins[i].clone(),
+                forced_win: None,
+            })
+            .collect();
+        // The search has the team it's asked about move next, so it's only right for the team
+        // whose turn it is, or for any team before anyone's had a turn
+        let to_move: Vec<Team> = match b.next_team() {
+            Some(team) => vec![team],
+            None => teams.to_vec(),
+        };
+        let positions = b.variant.searchable().then(|| {
+            to_move
+                .into_iter()
+                .map(|team| (team, Position::new(&b)))
+                .collect::<Vec<_>>()
+        });
+        (b.winner(), b.next_team(), hints, positions)
+    };
+
+    if let Some(positions) = positions {
+        // The teams searched share the time the client allowed
+        let mut limits = limits.clamped();
+        limits.time /= positions.len() as u32;
+        let searched = web::block(move || {
+            positions
+                .into_iter()
+                .map(|(team, position)| {
+                    let found = position.analyse(&team, &limits);
+                    (team, found)
+                })
+                .collect::<Vec<_>>()
+        })
+        .await;
+        match searched {
+            Ok(searched) => {
+                for (team, found) in searched {
+                    if let Some(hints) = hints.iter_mut().find(|hints| hints.team == team) {
+                        hints.forced_win = found.and_then(|(_, win_in)| win_in);
+                    }
+                }
+            }
+            Err(e) => {
+                println!("Search failed: {e}");
+                return HttpResponse::InternalServerError().finish();
+            }
+        }
+    }
+
+    HttpResponse::Ok().json(Analysis {
+        next: next.filter(|_| state == State::Incomplete),
+        state,
+        teams: hints,
+    })
+}
+
+/// Counts the ways to play `depth` more moves from the current position, to see how fast the
+/// search's board is.
+async fn perft_in(board_data: &BoardData, id: Uuid, depth: u32) -> HttpResponse {
+    let Some(game) = board_data.game(id) else {
+        return HttpResponse::NotFound().finish();
+    };
+    let (mut bits, team) = {
+        let b = game.board.lock().unwrap();
+        if b.variant != Variant::Classic {
+            let reason = format!(
+                "Only classic games can be counted, not {}\n",
+                b.variant.as_str()
+            );
+            return HttpResponse::BadRequest().body(reason);
+        }
+        (Bitboard::new(&b), b.next_team().unwrap_or(Team::Milk))
+    };
+    let depth = depth.min(Limits::MAX_DEPTH);
+
+    let started = Instant::now();
+    match web::block(move || bits.perft(depth, &team)).await {
+        Ok(Some(nodes)) => {
+            let elapsed = started.elapsed();
+            HttpResponse::Ok().json(serde_json::json!({
+                "depth": depth,
+                "nodes": nodes,
+                "ms": elapsed.as_millis(),
+                "nodes_per_second": (nodes as f64 / elapsed.as_secs_f64()) as u64,
+            }))
+        }
+        Ok(None) => HttpResponse::BadRequest().body(format!(
+            "That's more than {} positions, try a shallower depth\n",
+            Bitboard::MAX_PERFT_NODES
+        )),
+        Err(e) => {
+            println!("Perft failed: {e}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Starts the game over with `options`, or set up the way it is now if there aren't any. A
+/// lobby game restarts as its table was set up, and won't take any.
+async fn reset_in(
+    board_data: &BoardData,
+    id: Uuid,
+    options: Option<&GameOptions>,
+    req: &HttpRequest,
+) -> HttpResponse {
+    let Some(game) = board_data.game(id) else {
+        return HttpResponse::NotFound().finish();
+    };
+    let json = wants_json(req);
+    let current;
+    let (new_board, options) = match (&game.seated, options) {
+        (Some(_), Some(_)) => {
+            let b = game.board.lock().unwrap();
+            return reject(&MoveError::LobbyGame, &b, json);
+        }
+        (Some(table), None) => (lobby::table_board(table), table),
+        (None, options) => {
+            let options = match options {
+                Some(options) => options,
+                None => {
+                    let b = game.board.lock().unwrap();
+                    current = GameOptions::of(&b, game.auto_reply.load(Ordering::Relaxed));
+                    &current
+                }
+            };
+            match options.build() {
+                Ok(new_board) => (new_board, options),
+                Err(e) => return HttpResponse::BadRequest().body(e),
+            }
+        }
+    };
+    let _saving = game.saving.lock().await;
+    let mut res = board_data.save_start(id, &new_board).await;
+    if res.is_ok() {
+        res = board_data.save_auto_reply(id, options.opponent).await;
+    }
+
+    game.auto_reply.store(options.opponent, Ordering::Relaxed);
+    let mut b = game.board.lock().unwrap();
+    *b = new_board;
+    board_data.publish(id, || live::update_message(id, "reset", &b));
+    if let Err(e) = res {
+        println!("Failed to save reset of game {id}: {e}");
+    }
+    render(HttpResponse::Ok(), &b, json)
+}
+
+fn export_in(board_data: &BoardData, id: Uuid) -> HttpResponse {
+    let Some(game) = board_data.game(id) else {
+        return HttpResponse::NotFound().finish();
+    };
+    let b = game.board.lock().unwrap();
+    HttpResponse::Ok().body(format!("{}\n", b.notation()))
+}
+
+async fn import_in(board_data: &BoardData, id: Uuid, position: &str, json: bool) -> HttpResponse {
+    let Some(game) = board_data.game(id) else {
+        return HttpResponse::NotFound().finish();
+    };
+    let mut new_board: Board = match position.parse() {
+        Ok(new_board) => new_board,
+        Err(e) => return HttpResponse::BadRequest().body(format!("{e}\n")),
+    };
+    let _saving = game.saving.lock().await;
+    {
+        let b = game.board.lock().unwrap();
+        new_board.strict = b.strict;
+        if let Some(clock) = &b.clock {
+            new_board.first.get_or_insert(Team::Milk);
+            new_board.clock = Some(Clock::new(clock.control, Instant::now()));
+        }
+    }
+    let res = board_data.save_start(id, &new_board).await;
+
+    let mut b = game.board.lock().unwrap();
+    *b = new_board;
+    board_data.publish(id, || live::update_message(id, "import", &b));
+    if let Err(e) = res {
+        println!("Failed to save import into game {id}: {e}");
+    }
+    render(HttpResponse::Ok(), &b, json)
+}
+
+async fn random_in(
+    board_data: &BoardData,
+    id: Uuid,
+    seed: Option<u64>,
+    json: bool,
+) -> HttpResponse {
+    let Some(game) = board_data.game(id) else {
+        return HttpResponse::NotFound().finish();
+    };
+    let _saving = game.saving.lock().await;
+
+    let (position, res) = {
+        let mut b = game.board.lock().unwrap();
+        if let Some(seed) = seed {
+            b.reseed(seed);
+        }
+        b.random();
+        board_data.publish(id, || live::update_message(id, "random-board", &b));
+        (b.clone_position(), with_seed(HttpResponse::Ok(), &b))
+    };
+
+    // A random fill can't be replayed move by move, so it becomes the game's new start
+    if let Err(e) = board_data.save_start(id, &position).await {
+        println!("Failed to save random board of game {id}: {e}");
+    }
+    render(res, &position, json)
+}
+
+async fn random_game_in(
+    board_data: &BoardData,
+    id: Uuid,
+    seed: Option<u64>,
+    json: bool,
+) -> HttpResponse {
+    let Some(game) = board_data.game(id) else {
+        return HttpResponse::NotFound().finish();
+    };
+    let _saving = game.saving.lock().await;
+
+    let (start, moves, res) = {
+        let mut b = game.board.lock().unwrap();
+        if let Some(seed) = seed {
+            b.reseed(seed);
+        }
+        b.random_game();
+        board_data.publish(id, || live::update_message(id, "random-game", &b));
+
+        let mut start = Board::with_size(b.width, b.height, b.connect);
+        start.strict = b.strict;
+        start.variant = b.variant;
+        start.clock = b
+            .clock
+            .as_ref()
+            .map(|clock| Clock::new(clock.control, Instant::now()));
+        let res = render(with_seed(HttpResponse::Ok(), &b), &b, json);
+        (start, b.history.clone(), res)
+    };
+
+    // Unlike a random fill, this one was played out, so it's saved move by move
+    let saved = async {
+        board_data.save_start(id, &start).await?;
+        for (seq, m) in moves.iter().enumerate() {
+            board_data.save_move(id, seq + 1, m).await?;
+        }
+        Ok::<_, sqlx::Error>(())
+    };
+    if let Err(e) = saved.await {
+        println!("Failed to save random game {id}: {e}");
+    }
+    res
+}
+
+/// What `/12/analysis` makes of a board.
+#[derive(Serialize)]
+pub struct Analysis {
+    state: State,
+    next: Option<Team>,
+    teams: Vec<TeamHints>,
+}
+
+#[derive(Serialize)]
+pub struct TeamHints {
+    team: Team,
+    /// Moves that win straight away.
+    wins: Vec<Hint>,
+    /// Where the other teams win straight away unless this one gets there first.
+    blocks: Vec<Hint>,
+    /// How many moves of its own this team needs to win however the others play, if the
+    /// search found a way. Only classic and Pop Out games are searched, and only for the team
+    /// to move.
+    forced_win: Option<u32>,
+}
+
+/// A move on the board, with the column numbered from 1 like the `/12/place` route.
+#[derive(Clone, Serialize)]
+pub struct Hint {
+    column: u8,
+    #[serde(flatten)]
+    action: Action,
+}
+
+impl From<(u8, Action)> for Hint {
+    fn from((column, action): (u8, Action)) -> Self {
+        Self { column, action }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Depth {
+    depth: u32,
+}
+
+#[derive(Deserialize)]
+pub struct Seed {
+    seed: Option<u64>,
+}
+
+/// Settings for a new game. The board size defaults to the classic 4x4 connect-4.
+#[derive(Clone, Default, Deserialize)]
+pub struct GameOptions {
+    width: Option<usize>,
+    height: Option<usize>,
+    connect: Option<usize>,
+    /// Have the server answer every move with one for the other team.
+    #[serde(default)]
+    opponent: bool,
+    /// Make the teams take turns.
+    #[serde(default)]
+    strict: bool,
+    /// Seed for the board's random fills and games, instead of the usual 2024.
+    seed: Option<u64>,
+    #[serde(default)]
+    variant: Variant,
+    /// Seconds each team gets per move.
+    move_time: Option<u64>,
+    /// Seconds each team gets for the whole game, like a chess clock.
+    total_time: Option<u64>,
+}
+
+impl GameOptions {
+    pub fn build(&self) -> Result<Board, String> {
+        let width = self.width.unwrap_or(Board::DEFAULT_SIZE);
+        let height = self.height.unwrap_or(Board::DEFAULT_SIZE);
+        let connect = self.connect.unwrap_or(Board::DEFAULT_SIZE);
+        if !(1..=Board::MAX_SIZE).contains(&width) || !(1..=Board::MAX_SIZE).contains(&height) {
+            return Err(format!(
+                "width and height must be between 1 and {}",
+                Board::MAX_SIZE
+            ));
+        }
+        if connect == 0 || connect > width.max(height) {
+            return Err(format!(
+                "connect must be between 1 and {}",
+                width.max(height)
+            ));
+        }
+
+        if self.move_time == Some(0) || self.total_time == Some(0) {
+            return Err("move_time and total_time must be at least 1 second".to_string());
+        }
+        if [self.move_time, self.total_time]
+            .iter()
+            .flatten()
+            .any(|&secs| secs > TimeControl::MAX_SECONDS)
+        {
+            return Err(format!(
+                "move_time and total_time can be at most {} seconds",
+                TimeControl::MAX_SECONDS
+            ));
+        }
+
+        let mut b = Board::with_size(width, height, connect);
+        b.strict = self.strict;
+        b.variant = self.variant;
+        if let Some(control) = self.time_control() {
+            // A clock only makes sense with turns, and someone has to be first to move
+            b.strict = true;
+            b.first = Some(Team::Milk);
+            b.clock = Some(Clock::new(control, Instant::now()));
+        }
+        if let Some(seed) = self.seed {
+            b.reseed(seed);
+        }
+        Ok(b)
+    }
+
+    /// The options that would set up a fresh game like `b`.
+    pub fn of(b: &Board, opponent: bool) -> Self {
+        let control = b.clock.as_ref().map(|clock| clock.control);
+        Self {
+            width: Some(b.width),
+            height: Some(b.height),
+            connect: Some(b.connect),
+            opponent,
+            strict: b.strict,
+            seed: None,
+            variant: b.variant,
+            move_time: control.and_then(|control| control.move_time.map(|d| d.as_secs())),
+            total_time: control.and_then(|control| control.total_time.map(|d| d.as_secs())),
+        }
+    }
+
+    fn time_control(&self) -> Option<TimeControl> {
+        (self.move_time.is_some() || self.total_time.is_some()).then(|| TimeControl {
+            move_time: self.move_time.map(Duration::from_secs),
+            total_time: self.total_time.map(Duration::from_secs),
+        })
+    }
+
+    /// Whether players asking for these options and `other` can be put in the same game.
+    pub fn same_game(&self, other: &GameOptions) -> bool {
+        let size = |options: &GameOptions| {
+            (
+                options.width.unwrap_or(Board::DEFAULT_SIZE),
+                options.height.unwrap_or(Board::DEFAULT_SIZE),
+                options.connect.unwrap_or(Board::DEFAULT_SIZE),
+            )
+        };
+        size(self) == size(other)
+            && self.variant == other.variant
+            && self.time_control() == other.time_control()
+    }
 }
 
 #[derive(Clone, PartialEq, Eq)]
@@ -55,6 +1059,40 @@ pub enum Tile {
     Wall,
     Milk,
     Cookie,
+    Chocolate,
+}
+
+impl Tile {
+    /// The character used for this tile in saved positions.
+    fn to_char(&self) -> char {
+        match self {
+            Tile::Empty | Tile::Wall => '.',
+            Tile::Milk => 'm',
+            Tile::Cookie => 'c',
+            Tile::Chocolate => 'h',
+        }
+    }
+
+    fn from_char(c: char) -> Option<Self> {
+        match c {
+            '.' => Some(Tile::Empty),
+            'm' => Some(Tile::Milk),
+            'c' => Some(Tile::Cookie),
+            'h' => Some(Tile::Chocolate),
+            _ => None,
+        }
+    }
+}
+
+impl Tile {
+    fn team(&self) -> Option<Team> {
+        match self {
+            Tile::Milk => Some(Team::Milk),
+            Tile::Cookie => Some(Team::Cookie),
+            Tile::Chocolate => Some(Team::Chocolate),
+            Tile::Empty | Tile::Wall => None,
+        }
+    }
 }
 
 impl Display for Tile {
@@ -64,15 +1102,18 @@ impl Display for Tile {
             Tile::Wall => write!(f, "⬜"),
             Tile::Milk => write!(f, "🥛"),
             Tile::Cookie => write!(f, "🍪"),
+            Tile::Chocolate => write!(f, "🍫"),
         }
     }
 }
 
-#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Team {
     Milk,
     Cookie,
+    /// Only plays in [`Variant::Three`].
+    Chocolate,
 }
 
 impl Display for Team {
@@ -80,6 +1121,7 @@ impl Display for Team {
         match self {
             Team::Milk => write!(f, "🥛"),
             Team::Cookie => write!(f, "🍪"),
+            Team::Chocolate => write!(f, "🍫"),
         }
     }
 }
@@ -89,107 +1131,724 @@ impl Team {
         match self {
             Team::Milk => Tile::Milk,
             Team::Cookie => Tile::Cookie,
+            Team::Chocolate => Tile::Chocolate,
+        }
+    }
+
+    /// The opponent in a two-team game. See [`Board::after`] for three teams.
+    pub fn other(&self) -> Team {
+        match self {
+            Team::Milk => Team::Cookie,
+            Team::Cookie | Team::Chocolate => Team::Milk,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Team::Milk => "milk",
+            Team::Cookie => "cookie",
+            Team::Chocolate => "chocolate",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "milk" => Some(Team::Milk),
+            "cookie" => Some(Team::Cookie),
+            "chocolate" => Some(Team::Chocolate),
+            _ => None,
+        }
+    }
+}
+
+/// The rules a game is played by.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Variant {
+    #[default]
+    Classic,
+    /// Teams may also pop their own piece out of the bottom of a column, dropping the rest
+    /// down. A move that lines up both teams wins for whoever made it, and a full board is
+    /// only a draw once the next team has nothing to pop.
+    PopOut,
+    /// Milk, cookie and chocolate take turns in that order.
+    Three,
+    /// Pieces go on any empty cell instead of falling, and only a run of exactly `connect`
+    /// wins.
+    NoGravity,
+}
+
+impl Variant {
+    const TWO_TEAMS: [Team; 2] = [Team::Milk, Team::Cookie];
+    const THREE_TEAMS: [Team; 3] = [Team::Milk, Team::Cookie, Team::Chocolate];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Variant::Classic => "classic",
+            Variant::PopOut => "popout",
+            Variant::Three => "three",
+            Variant::NoGravity => "nogravity",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "classic" => Some(Variant::Classic),
+            "popout" => Some(Variant::PopOut),
+            "three" => Some(Variant::Three),
+            "nogravity" => Some(Variant::NoGravity),
+            _ => None,
+        }
+    }
+
+    pub fn teams(&self) -> &'static [Team] {
+        match self {
+            Variant::Three => &Self::THREE_TEAMS,
+            _ => &Self::TWO_TEAMS,
+        }
+    }
+
+    /// Whether the search in [`search`] plays it soundly, so its suggestions and forced wins
+    /// can be trusted. It only drops pieces for two teams, so it misses what a pop can do.
+    pub fn searchable(&self) -> bool {
+        *self == Variant::Classic
+    }
+
+    /// Whether the server can play it as an opponent. Dropping pieces is still a fair game of
+    /// Pop Out, just not the best one.
+    pub fn can_reply(&self) -> bool {
+        matches!(self, Variant::Classic | Variant::PopOut)
+    }
+}
+
+/// What a move does to its column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum Action {
+    /// Drops a piece onto the top of the pile.
+    Drop,
+    /// Takes the team's own piece out of the bottom, in [`Variant::PopOut`].
+    Pop,
+    /// Puts a piece on the given row, from 1 at the top, in [`Variant::NoGravity`].
+    Put { row: u8 },
+}
+
+impl Action {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::Drop => "drop",
+            Action::Pop => "pop",
+            Action::Put { .. } => "put",
+        }
+    }
+
+    pub fn parse(s: &str, row: Option<u8>) -> Option<Self> {
+        match (s, row) {
+            ("drop", _) => Some(Action::Drop),
+            ("pop", _) => Some(Action::Pop),
+            ("put", Some(row)) => Some(Action::Put { row }),
+            _ => None,
+        }
+    }
+
+    pub fn row(&self) -> Option<u8> {
+        match self {
+            Action::Put { row } => Some(*row),
+            Action::Drop | Action::Pop => None,
+        }
+    }
+}
+
+/// Why a move was turned down.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MoveError {
+    GameOver,
+    /// The team isn't in this variant.
+    NotPlaying,
+    OutOfRange,
+    /// The action isn't part of this variant.
+    WrongVariant,
+    /// Strict games only, carrying whose turn it is.
+    OutOfTurn(Team),
+    ColumnFull,
+    /// Only a team's own piece can be popped from the bottom.
+    CannotPop,
+    CellTaken,
+    NothingToUndo,
+    /// Lobby games only take changes from their players.
+    Unseated,
+    /// The player's token is for another team, carrying which.
+    WrongSeat(Team),
+    /// Lobby games are only played move by move, and restart as they were set up.
+    LobbyGame,
+}
+
+impl MoveError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            MoveError::GameOver | MoveError::ColumnFull => StatusCode::SERVICE_UNAVAILABLE,
+            MoveError::NotPlaying | MoveError::OutOfRange | MoveError::WrongVariant => {
+                StatusCode::BAD_REQUEST
+            }
+            MoveError::OutOfTurn(_)
+            | MoveError::CannotPop
+            | MoveError::CellTaken
+            | MoveError::NothingToUndo => StatusCode::CONFLICT,
+            MoveError::Unseated => StatusCode::UNAUTHORIZED,
+            MoveError::WrongSeat(_) | MoveError::LobbyGame => StatusCode::FORBIDDEN,
+        }
+    }
+
+    /// A stable name for JSON clients to match on.
+    pub fn code(&self) -> &'static str {
+        match self {
+            MoveError::GameOver => "game_over",
+            MoveError::NotPlaying => "not_playing",
+            MoveError::OutOfRange => "out_of_range",
+            MoveError::WrongVariant => "wrong_variant",
+            MoveError::OutOfTurn(_) => "out_of_turn",
+            MoveError::ColumnFull => "column_full",
+            MoveError::CannotPop => "cannot_pop",
+            MoveError::CellTaken => "cell_taken",
+            MoveError::NothingToUndo => "nothing_to_undo",
+            MoveError::Unseated => "unseated",
+            MoveError::WrongSeat(_) => "wrong_seat",
+            MoveError::LobbyGame => "lobby_game",
+        }
+    }
+}
+
+impl Display for MoveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MoveError::GameOver => write!(f, "The game is over"),
+            MoveError::NotPlaying => write!(f, "That team isn't playing"),
+            MoveError::OutOfRange => write!(f, "No such column"),
+            MoveError::WrongVariant => write!(f, "That move isn't allowed in this variant"),
+            MoveError::OutOfTurn(team) => write!(f, "It's {team}'s turn"),
+            MoveError::ColumnFull => write!(f, "The column is full"),
+            MoveError::CannotPop => write!(f, "Only your own piece can be popped"),
+            MoveError::CellTaken => write!(f, "The cell is taken"),
+            MoveError::NothingToUndo => write!(f, "Nothing to undo"),
+            MoveError::Unseated => write!(f, "This game needs a player token"),
+            MoveError::WrongSeat(team) => write!(f, "You're playing {team}"),
+            MoveError::LobbyGame => write!(f, "A lobby game can only be played move by move"),
         }
     }
 }
 
+/// A move in a column, numbered from 1 like the `/12/place` route.
+#[derive(Clone, Serialize)]
+pub struct Move {
+    pub team: Team,
+    pub column: u8,
+    #[serde(flatten)]
+    pub action: Action,
+}
+
+/// The ways a [`Line`] can step: right, down, down-right and down-left. Each runs only one
+/// way, so every line on the board is found once, from one end.
+const DIRECTIONS: [(isize, isize); 4] = [(1, 0), (0, 1), (1, 1), (-1, 1)];
+
+/// A run of `connect` cells on the board, starting at `x`, `y` (from the top left) and
+/// stepping by `dx`, `dy`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct Line {
+    x: isize,
+    y: isize,
+    dx: isize,
+    dy: isize,
+}
+
 pub struct Board {
     rng: rand::rngs::StdRng,
+    /// What `rng` was last seeded with.
+    pub seed: u64,
+    /// Random fills and games made since then.
+    pub rounds: u64,
     board: Vec<Tile>,
+    /// Every line one team fills, kept up to date as cells change so [`Board::winner`] doesn't
+    /// have to look at them all.
+    lines_won: Vec<(Line, Team)>,
+    /// How many cells hold a piece, kept alongside the cells so [`Board::winner`] can tell a
+    /// full board without counting.
+    placed: usize,
+    /// Moves made since the board was last reset or filled at random.
+    pub history: Vec<Move>,
+    pub width: usize,
+    pub height: usize,
+    /// How many in a row it takes to win.
+    pub connect: usize,
+    /// Whether the teams have to take turns.
+    pub strict: bool,
+    /// Who moves first when there's no history to go by, as set by an imported position.
+    pub first: Option<Team>,
+    pub variant: Variant,
+    /// The teams' clocks, in games with a time control.
+    pub clock: Option<Clock>,
 }
 
 impl Board {
+    pub const DEFAULT_SIZE: usize = 4;
+    pub const MAX_SIZE: usize = 32;
+    pub const DEFAULT_SEED: u64 = 2024;
+    const MAX_RANDOM_MOVES: usize = 4;
+
     pub fn new() -> Self {
-        const SIZE: usize = 4;
+        Self::with_size(Self::DEFAULT_SIZE, Self::DEFAULT_SIZE, Self::DEFAULT_SIZE)
+    }
+
+    pub fn with_size(width: usize, height: usize, connect: usize) -> Self {
         Board {
-            rng: rand::SeedableRng::seed_from_u64(2024),
-            board: vec![Tile::Empty; SIZE * SIZE],
+            rng: rand::SeedableRng::seed_from_u64(Self::DEFAULT_SEED),
+            seed: Self::DEFAULT_SEED,
+            rounds: 0,
+            board: vec![Tile::Empty; width * height],
+            lines_won: vec![],
+            placed: 0,
+            history: vec![],
+            width,
+            height,
+            connect,
+            strict: false,
+            first: None,
+            variant: Variant::Classic,
+            clock: None,
         }
     }
 
-    pub fn count_placed(&self) -> usize {
-        self.board.iter().filter(|&t| *t != Tile::Empty).count()
+    pub fn place(&mut self, team: Team, column: u8) -> Result<(), MoveError> {
+        self.play(team, column, Action::Drop)
+    }
+
+    pub fn play(&mut self, team: Team, column: u8, action: Action) -> Result<(), MoveError> {
+        self.play_at(team, column, action, Instant::now())
     }
 
-    pub fn place(&mut self, team: Team, column: u8) -> Result<(), ()> {
+    /// Plays a move made at `now`, as far as the clock's concerned.
+    pub fn play_at(
+        &mut self,
+        team: Team,
+        column: u8,
+        action: Action,
+        now: Instant,
+    ) -> Result<(), MoveError> {
+        // The background task may not have caught up with a team that's out of time yet
+        self.flag(now);
+        if self.winner() != State::Incomplete {
+            return Err(MoveError::GameOver);
+        }
+
+        if !self.variant.teams().contains(&team) || self.out_of_time(&team) {
+            return Err(MoveError::NotPlaying);
+        }
+
         // users are 1 indexed, system is 0 indexed
-        let column = column - 1;
+        let Some(column) = (column as usize).checked_sub(1) else {
+            return Err(MoveError::OutOfRange);
+        };
+
+        if column >= self.width {
+            return Err(MoveError::OutOfRange);
+        }
+
+        let allowed = match action {
+            Action::Drop => true,
+            Action::Pop => self.variant == Variant::PopOut,
+            Action::Put { .. } => self.variant == Variant::NoGravity,
+        };
+        if !allowed {
+            return Err(MoveError::WrongVariant);
+        }
 
-        const SIZE: usize = 4;
-        if column >= SIZE as u8 {
-            return Err(());
+        if let Some(next) = self.next_team().filter(|next| self.strict && *next != team) {
+            return Err(MoveError::OutOfTurn(next));
         }
 
-        let mut placed = false;
-        for y in (0..SIZE).rev() {
-            let i = y * SIZE + column as usize;
-            if self.board[i] == Tile::Empty {
-                self.board[i] = team.to_tile();
-                placed = true;
-                break;
+        let w = self.width;
+        let (action, rows) = match action {
+            Action::Drop => {
+                let Some(y) = (0..self.height)
+                    .rev()
+                    .find(|&y| self.board[y * w + column] == Tile::Empty)
+                else {
+                    return Err(MoveError::ColumnFull);
+                };
+                self.board[y * w + column] = team.to_tile();
+                self.placed += 1;
+                // Without gravity a piece can land under a floating one, so remember where
+                if self.variant == Variant::NoGravity {
+                    (Action::Put { row: y as u8 + 1 }, y..y + 1)
+                } else {
+                    (Action::Drop, y..y + 1)
+                }
+            }
+            Action::Pop => {
+                let bottom = (self.height - 1) * w + column;
+                if self.board[bottom] != team.to_tile() {
+                    return Err(MoveError::CannotPop);
+                }
+                for y in (1..self.height).rev() {
+                    self.board[y * w + column] = self.board[(y - 1) * w + column].clone();
+                }
+                self.board[column] = Tile::Empty;
+                self.placed -= 1;
+                (Action::Pop, 0..self.height)
             }
+            Action::Put { row } => {
+                let Some(y) = (row as usize).checked_sub(1).filter(|&y| y < self.height) else {
+                    return Err(MoveError::OutOfRange);
+                };
+                if self.board[y * w + column] != Tile::Empty {
+                    return Err(MoveError::CellTaken);
+                }
+                self.board[y * w + column] = team.to_tile();
+                self.placed += 1;
+                (action, y..y + 1)
+            }
+        };
+        self.rescan_column(column, rows);
+
+        if let Some(clock) = &mut self.clock {
+            clock.moved(&team, now);
         }
+        self.history.push(Move {
+            team,
+            column: column as u8 + 1,
+            action,
+        });
+        Ok(())
+    }
+
+    /// Puts the team to move out of the game if it has run out of time by `now`, giving back
+    /// that team.
+    pub fn flag(&mut self, now: Instant) -> Option<Team> {
+        if self.clock.is_none() || self.winner() != State::Incomplete {
+            return None;
+        }
+        let team = self.next_team()?;
+        let moves = self.history.len();
+        let clock = self.clock.as_mut()?;
+        clock.flag(&team, moves, now).then_some(team)
+    }
+
+    /// The teams that ran out of time, in the order they did.
+    pub fn timeouts(&self) -> &[Timeout] {
+        self.clock.as_ref().map_or(&[], |clock| &clock.flagged)
+    }
+
+    /// Whether `team` is out of the game for running out of time.
+    fn out_of_time(&self, team: &Team) -> bool {
+        self.clock.as_ref().is_some_and(|clock| clock.is_out(team))
+    }
 
-        if !placed {
-            return Err(());
+    /// Starts the clock over, as for a game starting now.
+    fn restart_clock(&mut self) {
+        if let Some(clock) = &mut self.clock {
+            *clock = Clock::new(clock.control, Instant::now());
         }
+    }
 
+    /// The moves that would win the game for `team` on the spot, whoever's turn it is.
+    pub fn winning_moves(&self, team: &Team) -> Vec<(u8, Action)> {
+        let won = State::won_by(team);
+        self.legal_moves(team)
+            .into_iter()
+            .filter(|&(column, action)| {
+                let mut b = self.clone_position();
+                b.strict = false;
+                b.play(team.clone(), column, action).is_ok() && b.winner() == won
+            })
+            .collect()
+    }
+
+    /// Every move `team` could make now, whoever's turn it is.
+    pub fn legal_moves(&self, team: &Team) -> Vec<(u8, Action)> {
+        let w = self.width;
+        let bottom = (self.height - 1) * w;
+        let mut moves = vec![];
+        for x in 0..w {
+            let column = x as u8 + 1;
+            if self.variant == Variant::NoGravity {
+                moves.extend(
+                    (0..self.height)
+                        .filter(|&y| self.board[y * w + x] == Tile::Empty)
+                        .map(|y| (column, Action::Put { row: y as u8 + 1 })),
+                );
+                continue;
+            }
+            // The top row shows which columns still have room
+            if self.board[x] == Tile::Empty {
+                moves.push((column, Action::Drop));
+            }
+            if self.variant == Variant::PopOut && self.board[bottom + x] == team.to_tile() {
+                moves.push((column, Action::Pop));
+            }
+        }
+        moves
+    }
+
+    /// The cells row by row from the top, one character each as in [`Tile::to_char`].
+    pub fn cells(&self) -> String {
+        self.board.iter().map(Tile::to_char).collect()
+    }
+
+    pub fn set_cells(&mut self, cells: &str) -> Result<(), String> {
+        let tiles = cells
+            .chars()
+            .map(|c| Tile::from_char(c).ok_or(format!("unknown cell {c:?}")))
+            .collect::<Result<Vec<_>, _>>()?;
+        if tiles.len() != self.board.len() {
+            return Err(format!(
+                "expected {} cells, got {}",
+                self.board.len(),
+                tiles.len()
+            ));
+        }
+
+        self.board = tiles;
+        self.placed = self.board.iter().filter(|&t| *t != Tile::Empty).count();
+        self.history.clear();
+        self.rescan();
         Ok(())
     }
 
-    pub fn winner(&self) -> State {
-        let mut cookie = false;
-        let mut milk = false;
-
-        let mut winning_states = vec![];
-        // All vertical wins
-        winning_states.extend(vec![
-            [0, 4, 8, 12],
-            [1, 5, 9, 13],
-            [2, 6, 10, 14],
-            [3, 7, 11, 15],
-        ]);
-        // All horizontal wins
-        winning_states.extend(vec![
-            [0, 1, 2, 3],
-            [4, 5, 6, 7],
-            [8, 9, 10, 11],
-            [12, 13, 14, 15],
-        ]);
-        // All diagonal wins
-        winning_states.extend(vec![[0, 5, 10, 15], [3, 6, 9, 12]]);
-
-        for state in winning_states {
-            let mut cookie_count = 0;
-            let mut milk_count = 0;
-            for i in state {
-                match self.board[i] {
-                    Tile::Cookie => cookie_count += 1,
-                    Tile::Milk => milk_count += 1,
-                    _ => (),
+    /// A board with the same size, cells and team to move, but a fresh random generator and no
+    /// history.
+    pub fn clone_position(&self) -> Board {
+        let mut b = Board::with_size(self.width, self.height, self.connect);
+        b.board = self.board.clone();
+        b.lines_won = self.lines_won.clone();
+        b.placed = self.placed;
+        b.strict = self.strict;
+        b.variant = self.variant;
+        b.first = self.next_team();
+        b.clock = self
+            .clock
+            .as_ref()
+            .map(|clock| Clock::new(clock.control, Instant::now()));
+        b
+    }
+
+    /// Whose turn it is, going by the last move. Anyone may start unless the position says so.
+    pub fn next_team(&self) -> Option<Team> {
+        match self.history.last() {
+            Some(m) => Some(self.after(&m.team)),
+            None => self.first.clone().map(|first| {
+                if self.out_of_time(&first) {
+                    self.after(&first)
+                } else {
+                    first
+                }
+            }),
+        }
+    }
+
+    /// Who plays after `team`, skipping teams that ran out of time.
+    pub fn after(&self, team: &Team) -> Team {
+        let turn_after = |team: &Team| match (self.variant, team) {
+            (Variant::Three, Team::Cookie) => Team::Chocolate,
+            _ => team.other(),
+        };
+        let mut next = turn_after(team);
+        while self.out_of_time(&next) && next != *team {
+            next = turn_after(&next);
+        }
+        next
+    }
+
+    /// Takes back the last move, if there's one on record.
+    pub fn undo(&mut self) -> Option<Move> {
+        let m = self.history.pop()?;
+        if let Some(clock) = &mut self.clock {
+            clock.undone(Instant::now());
+        }
+        let (w, column) = (self.width, m.column as usize - 1);
+        let rows = match m.action {
+            Action::Drop => {
+                // The last piece dropped into a column is the top one
+                let top = (0..self.height).find(|&y| self.board[y * w + column] != Tile::Empty);
+                if let Some(y) = top {
+                    self.board[y * w + column] = Tile::Empty;
+                    self.placed -= 1;
+                }
+                top.map_or(0..0, |y| y..y + 1)
+            }
+            Action::Pop => {
+                for y in 0..self.height - 1 {
+                    self.board[y * w + column] = self.board[(y + 1) * w + column].clone();
                 }
+                self.board[(self.height - 1) * w + column] = m.team.to_tile();
+                self.placed += 1;
+                0..self.height
             }
-            if cookie_count == 4 {
-                cookie = true;
+            Action::Put { row } => {
+                let y = row as usize - 1;
+                self.board[y * w + column] = Tile::Empty;
+                self.placed -= 1;
+                y..y + 1
             }
-            if milk_count == 4 {
-                milk = true;
+        };
+        self.rescan_column(column, rows);
+        Some(m)
+    }
+
+    /// A line filled by the winning team, if anyone has won.
+    pub fn winning_line(&self) -> Option<Vec<usize>> {
+        let team = self.winner().team()?;
+        let &(line, _) = self.lines_won.iter().find(|(_, owner)| *owner == team)?;
+        let w = self.width as isize;
+        Some(
+            (0..self.connect as isize)
+                .map(|i| ((line.y + line.dy * i) * w + line.x + line.dx * i) as usize)
+                .collect(),
+        )
+    }
+
+    /// The tile at `x`, `y`, if that's on the board.
+    fn tile_at(&self, x: isize, y: isize) -> Option<&Tile> {
+        let (w, h) = (self.width as isize, self.height as isize);
+        ((0..w).contains(&x) && (0..h).contains(&y)).then(|| &self.board[(y * w + x) as usize])
+    }
+
+    /// The team filling `line`, if one does.
+    fn line_owner(&self, line: Line) -> Option<Team> {
+        let k = self.connect as isize;
+        let at = |i: isize| self.tile_at(line.x + line.dx * i, line.y + line.dy * i);
+        let first = at(0)?;
+        let team = first.team()?;
+        if !(1..k).all(|i| at(i) == Some(first)) {
+            return None;
+        }
+        // Without gravity the run mustn't carry on past either end
+        if self.variant == Variant::NoGravity
+            && k > 1
+            && [-1, k].into_iter().any(|i| at(i) == Some(first))
+        {
+            return None;
+        }
+        Some(team)
+    }
+
+    /// Finds the lines one team fills all over again, after the whole board's changed.
+    fn rescan(&mut self) {
+        self.lines_won = self
+            .lines()
+            .filter_map(|line| Some((line, self.line_owner(line)?)))
+            .collect();
+    }
+
+    /// Brings [`Board::lines_won`] up to date after the cells in `rows` of `column` changed,
+    /// looking only at the lines through them or just past their ends.
+    fn rescan_column(&mut self, column: usize, rows: Range<usize>) {
+        let k = self.connect as isize;
+        let (w, h) = (self.width as isize, self.height as isize);
+        let x = column as isize;
+
+        // Kept in order as well, so the same moves always leave the same winning line first
+        let (mut near, mut seen) = (vec![], HashSet::new());
+        for y in rows.map(|y| y as isize) {
+            for (dx, dy) in DIRECTIONS {
+                // A line's owner can change when one of its cells does, or without gravity
+                // when the cell just before or after it does
+                for i in -1..=k {
+                    let line = Line {
+                        x: x - dx * i,
+                        y: y - dy * i,
+                        dx,
+                        dy,
+                    };
+                    let (end_x, end_y) = (line.x + dx * (k - 1), line.y + dy * (k - 1));
+                    let on_board = [(line.x, line.y), (end_x, end_y)]
+                        .iter()
+                        .all(|(x, y)| (0..w).contains(x) && (0..h).contains(y));
+                    if on_board && seen.insert(line) {
+                        near.push(line);
+                    }
+                }
             }
         }
 
-        let placed = self.count_placed();
-        match (cookie, milk, placed) {
-            (true, false, _) => State::Cookie,
-            (false, true, _) => State::Milk,
-            (true, true, _) => State::None,
-            (false, false, 16) => State::None,
-            _ => State::Incomplete,
+        self.lines_won.retain(|(line, _)| !seen.contains(line));
+        for line in near {
+            if let Some(team) = self.line_owner(line) {
+                self.lines_won.push((line, team));
+            }
         }
     }
 
+    /// Every run of `connect` cells that wins the game if one team fills it.
+    fn lines(&self) -> impl Iterator<Item = Line> + '_ {
+        let k = self.connect as isize;
+        let (w, h) = (self.width as isize, self.height as isize);
+
+        (0..h)
+            .flat_map(move |y| (0..w).map(move |x| (x, y)))
+            .flat_map(move |(x, y)| {
+                DIRECTIONS
+                    .iter()
+                    .map(move |&(dx, dy)| Line { x, y, dx, dy })
+            })
+            .filter(move |line| {
+                let (end_x, end_y) = (line.x + line.dx * (k - 1), line.y + line.dy * (k - 1));
+                (0..w).contains(&end_x) && (0..h).contains(&end_y)
+            })
+    }
+
+    pub fn winner(&self) -> State {
+        // Running out of time loses, and the last team left in wins
+        let mut left = self
+            .variant
+            .teams()
+            .iter()
+            .filter(|team| !self.out_of_time(team));
+        if let (Some(last), None) = (left.next(), left.next()) {
+            return State::won_by(last);
+        }
+
+        let mut owners = self.lines_won.iter().map(|(_, team)| team);
+        match owners.next() {
+            Some(first) if owners.all(|team| team == first) => State::won_by(first),
+            None if self.placed < self.board.len() => State::Incomplete,
+            // A full Pop Out board plays on while there's something to pop
+            None if self.variant == Variant::PopOut
+                && self.next_team().map_or_else(
+                    || {
+                        self.legal_moves(&Team::Milk).len() + self.legal_moves(&Team::Cookie).len()
+                            > 0
+                    },
+                    |team| !self.legal_moves(&team).is_empty(),
+                ) =>
+            {
+                State::Incomplete
+            }
+            None => State::None,
+            // Only a pop can line up more than one team, and it's won by whoever popped
+            Some(_) if self.variant == Variant::PopOut => self
+                .history
+                .last()
+                .filter(|m| self.lines_won.iter().any(|(_, team)| *team == m.team))
+                .map_or(State::None, |m| State::won_by(&m.team)),
+            _ => State::None,
+        }
+    }
+
+    pub fn reseed(&mut self, seed: u64) {
+        self.rng = rand::SeedableRng::seed_from_u64(seed);
+        self.seed = seed;
+        self.rounds = 0;
+    }
+
     pub fn random(&mut self) {
-        const SIZE: usize = 4;
-        for i in 0..SIZE * SIZE {
+        self.rounds += 1;
+        self.history.clear();
+        self.restart_clock();
+        let teams = self.variant.teams();
+        for i in 0..self.board.len() {
+            if teams.len() > 2 {
+                self.board[i] = teams[self.rng.gen_range(0..teams.len())].to_tile();
+                continue;
+            }
             let state = self.rng.gen::<bool>();
             if state {
                 self.board[i] = Tile::Cookie;
@@ -197,46 +1856,448 @@ impl Board {
                 self.board[i] = Tile::Milk;
             }
         }
+        self.placed = self.board.len();
+        self.rescan();
+    }
+
+    /// Clears the board and plays random legal moves, the teams taking turns, until the game
+    /// is over. Pop Out games can go round in circles, so they stop unfinished after
+    /// [`Board::MAX_RANDOM_MOVES`] moves per cell.
+    pub fn random_game(&mut self) {
+        self.rounds += 1;
+        self.board.fill(Tile::Empty);
+        self.lines_won.clear();
+        self.placed = 0;
+        self.history.clear();
+        self.first = None;
+        self.restart_clock();
+
+        let teams = self.variant.teams();
+        let mut team = if teams.len() > 2 {
+            teams[self.rng.gen_range(0..teams.len())].clone()
+        } else if self.rng.gen::<bool>() {
+            Team::Cookie
+        } else {
+            Team::Milk
+        };
+        let limit = Self::MAX_RANDOM_MOVES * self.board.len();
+        while self.winner() == State::Incomplete && self.history.len() < limit {
+            let moves = self.legal_moves(&team);
+            if !moves.is_empty() {
+                let (column, action) = moves[self.rng.gen_range(0..moves.len())];
+                self.play(team.clone(), column, action)
+                    .expect("the move is legal");
+            }
+            team = self.after(&team);
+        }
     }
 }
 
 impl Display for Board {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        const SIZE: usize = 4;
-        for y in 0..SIZE {
+        for y in 0..self.height {
             write!(f, "{}", Tile::Wall)?;
-            for x in 0..SIZE {
-                write!(f, "{}", self.board[y * SIZE + x])?;
+            for x in 0..self.width {
+                write!(f, "{}", self.board[y * self.width + x])?;
             }
             write!(f, "{}", Tile::Wall)?;
             writeln!(f)?;
         }
-        for _ in 0..SIZE + 2 {
+        for _ in 0..self.width + 2 {
             write!(f, "{}", Tile::Wall)?;
         }
         writeln!(f)?;
 
         match self.winner() {
-            State::Cookie => writeln!(f, "{} wins!", Team::Cookie)?,
-            State::Milk => writeln!(f, "{} wins!", Team::Milk)?,
             State::None => writeln!(f, "No winner.")?,
             State::Incomplete => (),
+            state => {
+                if let Some(team) = state.team() {
+                    writeln!(f, "{team} wins!")?
+                }
+            }
         };
 
         Ok(())
     }
 }
 
-#[derive(Clone, PartialEq, Eq)]
+#[derive(Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum State {
     Cookie,
     Milk,
+    Chocolate,
     None,
     Incomplete,
 }
 
-pub type BoardData = Mutex<Board>;
+impl State {
+    fn won_by(team: &Team) -> Self {
+        match team {
+            Team::Milk => State::Milk,
+            Team::Cookie => State::Cookie,
+            Team::Chocolate => State::Chocolate,
+        }
+    }
+
+    /// The team that won, if the game's been won.
+    pub fn team(&self) -> Option<Team> {
+        match self {
+            State::Milk => Some(Team::Milk),
+            State::Cookie => Some(Team::Cookie),
+            State::Chocolate => Some(Team::Chocolate),
+            State::None | State::Incomplete => None,
+        }
+    }
+}
+
+/// The board as sent to clients that ask for JSON.
+#[derive(Serialize)]
+pub struct BoardView {
+    width: usize,
+    height: usize,
+    connect: usize,
+    variant: Variant,
+    /// Rows from the top, with `null` for empty cells.
+    grid: Vec<Vec<Option<Team>>>,
+    state: State,
+    /// Whose turn it is, while the game is on and someone has moved.
+    next: Option<Team>,
+    winning_line: Option<Vec<Cell>>,
+    /// Time left for every team, in games with a time control.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    clock: Option<ClockView>,
+}
+
+/// A cell on the board, with the column numbered from 1 like the `/12/place` route and the
+/// row from 1 at the top.
+#[derive(Serialize)]
+pub struct Cell {
+    column: usize,
+    row: usize,
+}
+
+impl BoardView {
+    pub fn new(b: &Board) -> Self {
+        let state = b.winner();
+        let next = b.next_team().filter(|_| state == State::Incomplete);
+        Self {
+            width: b.width,
+            height: b.height,
+            connect: b.connect,
+            variant: b.variant,
+            grid: b
+                .board
+                .chunks(b.width)
+                .map(|row| row.iter().map(Tile::team).collect())
+                .collect(),
+            clock: b
+                .clock
+                .as_ref()
+                .map(|clock| ClockView::new(clock, b.variant.teams(), next.as_ref())),
+            next,
+            state,
+            winning_line: b.winning_line().map(|line| {
+                line.into_iter()
+                    .map(|i| Cell {
+                        column: i % b.width + 1,
+                        row: i / b.width + 1,
+                    })
+                    .collect()
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_classic_lines() {
+        // 4 columns, 4 rows and the two diagonals, same as the old hand-written list
+        assert_eq!(Board::new().lines().count(), 10);
+    }
+
+    #[test]
+    fn test_connect_four() {
+        let mut b = Board::with_size(7, 6, 4);
+        assert_eq!(b.lines().count(), 69);
+        for column in [1, 2, 3] {
+            b.place(Team::Milk, column).unwrap();
+            b.place(Team::Cookie, column).unwrap();
+        }
+        assert!(b.winner() == State::Incomplete);
+        b.place(Team::Milk, 4).unwrap();
+        assert!(b.winner() == State::Milk);
+    }
+
+    #[test]
+    fn test_cells() {
+        let mut b = Board::with_size(3, 2, 2);
+        b.place(Team::Milk, 1).unwrap();
+        b.place(Team::Cookie, 3).unwrap();
+        assert_eq!(b.cells(), "...m.c");
+        assert_eq!(b.history.len(), 2);
+
+        let mut copy = Board::with_size(3, 2, 2);
+        copy.set_cells(&b.cells()).unwrap();
+        assert_eq!(copy.cells(), b.cells());
+        assert!(copy.set_cells("...").is_err());
+        assert!(copy.set_cells("...x..").is_err());
+    }
+
+    #[test]
+    fn test_undo() {
+        let mut b = Board::with_size(3, 3, 3);
+        assert!(b.undo().is_none());
+        b.place(Team::Milk, 2).unwrap();
+        b.place(Team::Cookie, 2).unwrap();
+        assert!(b.next_team() == Some(Team::Milk));
+
+        let m = b.undo().unwrap();
+        assert!(m.team == Team::Cookie && m.column == 2);
+        assert_eq!(b.cells(), ".......m.");
+        assert!(b.next_team() == Some(Team::Cookie));
+    }
+
+    #[test]
+    fn test_move_errors() {
+        let mut b = Board::with_size(2, 1, 2);
+        b.strict = true;
+        assert_eq!(b.place(Team::Milk, 3), Err(MoveError::OutOfRange));
+        assert_eq!(b.place(Team::Milk, 0), Err(MoveError::OutOfRange));
+        b.place(Team::Milk, 1).unwrap();
+        assert_eq!(
+            b.place(Team::Milk, 2),
+            Err(MoveError::OutOfTurn(Team::Cookie))
+        );
+        assert_eq!(b.place(Team::Cookie, 1), Err(MoveError::ColumnFull));
+        b.place(Team::Cookie, 2).unwrap();
+        assert_eq!(b.place(Team::Milk, 2), Err(MoveError::GameOver));
+    }
+
+    #[test]
+    fn test_board_view() {
+        let mut b = Board::with_size(3, 2, 2);
+        b.place(Team::Milk, 1).unwrap();
+        b.place(Team::Cookie, 3).unwrap();
+        b.place(Team::Milk, 1).unwrap();
+        let view = serde_json::to_value(BoardView::new(&b)).unwrap();
+        assert_eq!(
+            view,
+            serde_json::json!({
+                "width": 3,
+                "height": 2,
+                "connect": 2,
+                "variant": "classic",
+                "grid": [["milk", null, null], ["milk", null, "cookie"]],
+                "state": "milk",
+                "next": null,
+                "winning_line": [{ "column": 1, "row": 1 }, { "column": 1, "row": 2 }],
+            })
+        );
+    }
 
-pub fn board_data() -> BoardData {
-    Mutex::new(Board::new())
+    #[test]
+    fn test_seeds() {
+        let mut b = Board::new();
+        b.random();
+        let first_fill = b.cells();
+        b.random();
+        assert_eq!(b.rounds, 2);
+
+        b.reseed(Board::DEFAULT_SEED);
+        b.random();
+        assert_eq!(b.cells(), first_fill);
+
+        let mut other = Board::with_size(7, 6, 4);
+        b = Board::with_size(7, 6, 4);
+        b.reseed(5);
+        other.reseed(5);
+        b.random_game();
+        other.random_game();
+        assert_eq!(b.cells(), other.cells());
+        assert!(b.winner() != State::Incomplete);
+        assert_eq!(b.history.len(), b.placed);
+    }
+
+    #[test]
+    fn test_variants() {
+        let mut b = Board::with_size(3, 3, 3);
+        b.variant = Variant::PopOut;
+        b.place(Team::Milk, 1).unwrap();
+        b.place(Team::Cookie, 1).unwrap();
+        assert_eq!(
+            b.play(Team::Cookie, 1, Action::Pop),
+            Err(MoveError::CannotPop)
+        );
+        b.play(Team::Milk, 1, Action::Pop).unwrap();
+        assert_eq!(b.cells(), "......c..");
+        b.undo().unwrap();
+        assert_eq!(b.cells(), "...c..m..");
+        assert_eq!(
+            b.play(Team::Milk, 1, Action::Put { row: 1 }),
+            Err(MoveError::WrongVariant)
+        );
+
+        let mut b = Board::with_size(3, 3, 3);
+        b.variant = Variant::Three;
+        b.place(Team::Milk, 1).unwrap();
+        b.place(Team::Cookie, 2).unwrap();
+        assert_eq!(b.next_team(), Some(Team::Chocolate));
+        b.place(Team::Chocolate, 3).unwrap();
+        assert_eq!(b.next_team(), Some(Team::Milk));
+        assert_eq!(b.notation(), ".../.../mch m 3 three");
+        assert!(".../.../mch m 3".parse::<Board>().is_err());
+
+        let mut b = Board::with_size(4, 1, 2);
+        b.variant = Variant::NoGravity;
+        b.play(Team::Milk, 1, Action::Put { row: 1 }).unwrap();
+        b.play(Team::Milk, 2, Action::Put { row: 1 }).unwrap();
+        assert!(b.winner() == State::Milk);
+
+        // Three in a row is one too many
+        b.set_cells("mmm.").unwrap();
+        assert!(b.winner() == State::Incomplete);
+        assert_eq!(
+            b.play(Team::Cookie, 2, Action::Put { row: 1 }),
+            Err(MoveError::CellTaken)
+        );
+    }
+
+    #[test]
+    fn test_lines_won() {
+        // What's kept up to date move by move matches looking at every line again
+        let matches_rescan = |b: &Board| {
+            let mut rescanned = b.clone_position();
+            rescanned.rescan();
+            b.placed == b.board.iter().filter(|&t| *t != Tile::Empty).count()
+                && rescanned.lines_won.len() == b.lines_won.len()
+                && rescanned
+                    .lines_won
+                    .iter()
+                    .all(|(line, team)| b.lines_won.contains(&(*line, team.clone())))
+        };
+        for variant in [
+            Variant::Classic,
+            Variant::PopOut,
+            Variant::NoGravity,
+            Variant::Three,
+        ] {
+            for seed in 0..20 {
+                let mut b = Board::with_size(5, 4, 3);
+                b.variant = variant;
+                b.reseed(seed);
+                b.random_game();
+                assert!(matches_rescan(&b));
+                while b.undo().is_some() {
+                    assert!(matches_rescan(&b));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_winning_moves() {
+        let mut b = Board::new();
+        b.set_cells("........mmm.cccm").unwrap();
+        assert_eq!(b.winning_moves(&Team::Milk), vec![(4, Action::Drop)]);
+        assert!(b.winning_moves(&Team::Cookie).is_empty());
+
+        let (column, win_in) = Position::new(&b)
+            .analyse(&Team::Milk, &Limits::default())
+            .unwrap();
+        assert_eq!((column, win_in), (4, Some(1)));
+    }
+
+    #[test]
+    fn test_search() {
+        let mut b = Board::with_size(7, 6, 4);
+        for column in [1, 2, 3] {
+            b.place(Team::Milk, column).unwrap();
+            b.place(Team::Cookie, column).unwrap();
+        }
+        // Milk takes the win, cookie blocks it
+        assert_eq!(
+            Position::new(&b).best_move(&Team::Milk, &Limits::default()),
+            Some(4)
+        );
+        assert_eq!(
+            Position::new(&b).best_move(&Team::Cookie, &Limits::default()),
+            Some(4)
+        );
+
+        b.place(Team::Milk, 4).unwrap();
+        assert_eq!(
+            Position::new(&b).best_move(&Team::Cookie, &Limits::default()),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reset_keeps_options() {
+        let board_data = BoardData::unsaved();
+        let options = GameOptions {
+            width: Some(5),
+            height: Some(3),
+            connect: Some(3),
+            opponent: true,
+            strict: true,
+            variant: Variant::PopOut,
+            total_time: Some(60),
+            ..GameOptions::default()
+        };
+        let id = board_data.create(options.build().unwrap(), true).await;
+        board_data
+            .game(id)
+            .unwrap()
+            .board
+            .lock()
+            .unwrap()
+            .place(Team::Milk, 2)
+            .unwrap();
+
+        let req = actix_web::test::TestRequest::default().to_http_request();
+        let res = reset_in(&board_data, id, None, &req).await;
+        assert!(res.status().is_success());
+        let game = board_data.game(id).unwrap();
+        assert!(game.auto_reply.load(Ordering::Relaxed));
+        let b = game.board.lock().unwrap();
+        assert!(b.history.is_empty());
+        assert_eq!((b.width, b.height, b.connect), (5, 3, 3));
+        assert!(b.strict && b.variant == Variant::PopOut);
+        let control = b.clock.as_ref().unwrap().control;
+        assert_eq!(control.total_time, Some(Duration::from_secs(60)));
+    }
+
+    #[tokio::test]
+    async fn test_pop_out_not_searched() {
+        let mut b = Board::with_size(7, 3, 3);
+        b.variant = Variant::PopOut;
+        b.set_cells("....c.m....mcc.m..cmm").unwrap();
+        // Milk can make two threats at once, which is a win as far as drops go...
+        let found = Position::new(&b).analyse(&Team::Milk, &Limits::default());
+        let Some((column, Some(2))) = found else {
+            panic!("expected a win in 2, got {found:?}");
+        };
+        // ...but cookie pops 5 and lines up its middle row first
+        let mut popped = b.clone_position();
+        popped.variant = Variant::PopOut;
+        popped.place(Team::Milk, column).unwrap();
+        popped.play(Team::Cookie, 5, Action::Pop).unwrap();
+        assert!(popped.winner() == State::Cookie);
+
+        let board_data = BoardData::unsaved();
+        let id = board_data.create(b, false).await;
+        let res = suggest_in(&board_data, id, Team::Milk, &Limits::default()).await;
+        assert_eq!(res.status(), actix_web::http::StatusCode::BAD_REQUEST);
+        let res = analysis_in(&board_data, id, &Limits::default()).await;
+        let body = actix_web::body::to_bytes(res.into_body()).await.unwrap();
+        let found: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        for hints in found["teams"].as_array().unwrap() {
+            assert!(hints["forced_win"].is_null());
+        }
+    }
 }
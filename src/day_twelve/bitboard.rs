@@ -0,0 +1,262 @@
+use super::{Board, State, Team, DIRECTIONS};
+
+/// A set of cells, one bit each.
+#[derive(Clone)]
+struct Bits(Vec<u64>);
+
+impl Bits {
+    fn new(len: usize) -> Self {
+        Bits(vec![0; len.div_ceil(64)])
+    }
+
+    fn get(&self, bit: usize) -> bool {
+        self.0[bit / 64] >> (bit % 64) & 1 == 1
+    }
+
+    fn flip(&mut self, bit: usize) {
+        self.0[bit / 64] ^= 1 << (bit % 64);
+    }
+}
+
+/// A two-team, drops-only copy of a [`Board`] for the search to play on, with a bit per
+/// cell and team.
+///
+/// Cells are numbered a column at a time from the bottom, with a spare bit on top of every
+/// column. The spare bits are never set, so a run of pieces can't wrap from one column into
+/// the next and a win only needs checking around the piece just played.
+#[derive(Clone)]
+pub struct Bitboard {
+    width: usize,
+    height: usize,
+    connect: usize,
+    /// Milk's pieces, then cookie's.
+    pieces: [Bits; 2],
+    /// How many pieces are in each column.
+    heights: Vec<usize>,
+    /// The columns played since the copy was made and who by, for taking them back.
+    moves: Vec<(usize, usize)>,
+    /// The [Zobrist hash](https://en.wikipedia.org/wiki/Zobrist_hashing) of the pieces.
+    hash: u64,
+    state: State,
+}
+
+impl Bitboard {
+    /// Counting more positions than this takes too long to answer a request.
+    pub const MAX_PERFT_NODES: u64 = 50_000_000;
+
+    pub fn new(b: &Board) -> Self {
+        let mut bits = Self {
+            width: b.width,
+            height: b.height,
+            connect: b.connect,
+            pieces: [
+                Bits::new(b.width * (b.height + 1)),
+                Bits::new(b.width * (b.height + 1)),
+            ],
+            heights: vec![0; b.width],
+            moves: vec![],
+            hash: 0,
+            state: b.winner(),
+        };
+        for x in 0..b.width {
+            // Pieces sit on each other, so a column's pieces are the ones below its first gap
+            for y in (0..b.height).rev() {
+                let Some(team) = b.board[y * b.width + x].team() else {
+                    break;
+                };
+                let bit = bits.bit(x, bits.heights[x]);
+                bits.pieces[index(&team)].flip(bit);
+                bits.hash ^= zobrist(index(&team), bit);
+                bits.heights[x] += 1;
+            }
+        }
+        bits
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn hash(&self) -> u64 {
+        self.hash
+    }
+
+    pub fn state(&self) -> &State {
+        &self.state
+    }
+
+    /// The bit for the cell `row` pieces up from the bottom of `column`.
+    fn bit(&self, column: usize, row: usize) -> usize {
+        column * (self.height + 1) + row
+    }
+
+    /// Which team has a piece on `bit`, as an index into `pieces`.
+    pub fn owner(&self, bit: usize) -> Option<usize> {
+        (0..2).find(|&team| self.pieces[team].get(bit))
+    }
+
+    pub fn can_drop(&self, column: usize) -> bool {
+        self.state == State::Incomplete && self.heights[column] < self.height
+    }
+
+    /// Drops a piece into `column`, which must have room, returning the bit it landed on.
+    pub fn drop(&mut self, column: usize, team: &Team) -> usize {
+        let bit = self.bit(column, self.heights[column]);
+        let team = index(team);
+        self.pieces[team].flip(bit);
+        self.hash ^= zobrist(team, bit);
+        self.heights[column] += 1;
+        self.moves.push((column, team));
+
+        if self.wins_at(bit, team) {
+            self.state = if team == 0 {
+                State::Milk
+            } else {
+                State::Cookie
+            };
+        } else if self.heights.iter().all(|&h| h == self.height) {
+            self.state = State::None;
+        }
+        bit
+    }
+
+    /// Takes back the last [`Bitboard::drop`].
+    pub fn undo(&mut self) {
+        let Some((column, team)) = self.moves.pop() else {
+            return;
+        };
+        self.heights[column] -= 1;
+        let bit = self.bit(column, self.heights[column]);
+        self.pieces[team].flip(bit);
+        self.hash ^= zobrist(team, bit);
+        // No move is made once the game is over, so it wasn't before this one
+        self.state = State::Incomplete;
+    }
+
+    /// Whether `team`'s piece on `bit` completes a line.
+    fn wins_at(&self, bit: usize, team: usize) -> bool {
+        let stride = self.height + 1;
+        let len = self.width * stride;
+        let pieces = &self.pieces[team];
+        // Up, across, and the two diagonals
+        [1, stride, stride + 1, stride - 1].into_iter().any(|step| {
+            let up = (1..)
+                .map(|n| bit + n * step)
+                .take_while(|&b| b < len && pieces.get(b))
+                .count();
+            let down = (1..)
+                .map_while(|n| bit.checked_sub(n * step))
+                .take_while(|&b| pieces.get(b))
+                .count();
+            1 + up + down >= self.connect
+        })
+    }
+
+    /// Every run of `connect` cells that wins if one team fills it, as bits.
+    pub fn lines(&self) -> Vec<Vec<usize>> {
+        // Rows count up here rather than down, which only turns the diagonals over. Each line
+        // is still found once, and the search doesn't care about the order
+        let k = self.connect as isize;
+        let (w, h) = (self.width as isize, self.height as isize);
+
+        let mut lines = vec![];
+        for x in 0..w {
+            for y in 0..h {
+                for (dx, dy) in DIRECTIONS {
+                    let (end_x, end_y) = (x + dx * (k - 1), y + dy * (k - 1));
+                    if (0..w).contains(&end_x) && (0..h).contains(&end_y) {
+                        lines.push(
+                            (0..k)
+                                .map(|i| self.bit((x + dx * i) as usize, (y + dy * i) as usize))
+                                .collect(),
+                        );
+                    }
+                }
+            }
+        }
+        lines
+    }
+
+    /// How many ways there are to play `depth` more moves, `team` first, counting a game that
+    /// ends early as no ways at all. `None` once that's more than
+    /// [`Bitboard::MAX_PERFT_NODES`] positions to visit.
+    pub fn perft(&mut self, depth: u32, team: &Team) -> Option<u64> {
+        let mut budget = Self::MAX_PERFT_NODES;
+        self.count(depth, team, &mut budget)
+    }
+
+    fn count(&mut self, depth: u32, team: &Team, budget: &mut u64) -> Option<u64> {
+        *budget = budget.checked_sub(1)?;
+        if depth == 0 {
+            return Some(1);
+        }
+
+        let mut nodes = 0;
+        for column in 0..self.width {
+            if !self.can_drop(column) {
+                continue;
+            }
+            self.drop(column, team);
+            let counted = self.count(depth - 1, &team.other(), budget);
+            self.undo();
+            nodes += counted?;
+        }
+        Some(nodes)
+    }
+}
+
+/// Where a team's pieces go in [`Bitboard::pieces`]. Chocolate doesn't play the search's games.
+pub fn index(team: &Team) -> usize {
+    match team {
+        Team::Milk => 0,
+        Team::Cookie | Team::Chocolate => 1,
+    }
+}
+
+/// A fixed random number for `team` having a piece on `bit`, from
+/// [SplitMix64](https://prng.di.unimi.it/splitmix64.c) so there's no table to build.
+fn zobrist(team: usize, bit: usize) -> u64 {
+    let mut z = ((bit as u64) << 1 | team as u64)
+        .wrapping_add(1)
+        .wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bitboard() {
+        assert_eq!(
+            Bitboard::new(&Board::new()).perft(4, &Team::Milk),
+            Some(256)
+        );
+
+        // Milk winning in the first column ends that game early
+        let mut b = Board::new();
+        b.set_cells("....m...m...m...").unwrap();
+        assert_eq!(Bitboard::new(&b).perft(2, &Team::Milk), Some(12));
+
+        let mut one = Bitboard::new(&Board::new());
+        let mut other = one.clone();
+        for (column, team) in [(0, Team::Milk), (1, Team::Cookie), (2, Team::Milk)] {
+            one.drop(column, &team);
+        }
+        for (column, team) in [(2, Team::Milk), (1, Team::Cookie), (0, Team::Milk)] {
+            other.drop(column, &team);
+        }
+        assert_eq!(one.hash(), other.hash());
+        one.undo();
+        assert_ne!(one.hash(), other.hash());
+
+        // Every line of the board, each once
+        let mut lines = Bitboard::new(&Board::with_size(7, 6, 4)).lines();
+        lines.iter_mut().for_each(|line| line.sort());
+        lines.sort();
+        lines.dedup();
+        assert_eq!(lines.len(), 69);
+    }
+}
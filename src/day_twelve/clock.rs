@@ -0,0 +1,315 @@
+use std::time::{Duration, Instant};
+
+use actix_web::web::Data;
+use serde::Serialize;
+
+use super::{live, BoardData, Team};
+
+/// How long the teams get to think, per move, in total, or both.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeControl {
+    pub move_time: Option<Duration>,
+    pub total_time: Option<Duration>,
+}
+
+impl TimeControl {
+    /// The most seconds a game can give a team, per move or in total. A week is plenty, and
+    /// it's stored as a Postgres `integer`.
+    pub const MAX_SECONDS: u64 = 7 * 24 * 60 * 60;
+}
+
+/// A chess clock for every team in a game with a [`TimeControl`].
+///
+/// The clock starts with the game, so a team that never makes its first move still runs out
+/// of time. A team that does is out of the game, and with three teams the other two play on.
+#[derive(Clone)]
+pub struct Clock {
+    pub control: TimeControl,
+    /// Time spent by milk, cookie and chocolate, not counting the move being thought about.
+    used: [Duration; 3],
+    /// When the team to move started thinking.
+    turn_started: Instant,
+    /// The teams that ran out of time, in the order they did.
+    pub flagged: Vec<Timeout>,
+}
+
+/// A team running out of time, after `moves` moves of the game had been made.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Timeout {
+    pub team: Team,
+    pub moves: usize,
+}
+
+impl Clock {
+    /// How often the background task looks for teams out of time.
+    const TICK: Duration = Duration::from_millis(100);
+
+    /// A clock for a game that started at `started`, with the first team to move thinking.
+    pub fn new(control: TimeControl, started: Instant) -> Self {
+        Self {
+            control,
+            used: [Duration::ZERO; 3],
+            turn_started: started,
+            flagged: vec![],
+        }
+    }
+
+    /// Charges `team` for the move it just made at `now`, and starts the next team's turn.
+    pub fn moved(&mut self, team: &Team, now: Instant) {
+        self.used[slot(team)] += now.saturating_duration_since(self.turn_started);
+        self.turn_started = now;
+    }
+
+    /// Gives the team to move a fresh turn after a move's taken back. Time already spent on
+    /// the move stays spent.
+    pub fn undone(&mut self, now: Instant) {
+        self.turn_started = now;
+    }
+
+    /// Whether `team` has run out of time.
+    pub fn is_out(&self, team: &Team) -> bool {
+        self.flagged.iter().any(|timeout| timeout.team == *team)
+    }
+
+    /// The time `team` has left at `now`, if the time control limits it. `to_move` is the
+    /// team whose clock is running.
+    pub fn left(&self, team: &Team, to_move: Option<&Team>, now: Instant) -> Option<Duration> {
+        if self.is_out(team) {
+            return Some(Duration::ZERO);
+        }
+        let thinking = if to_move == Some(team) {
+            now.saturating_duration_since(self.turn_started)
+        } else {
+            Duration::ZERO
+        };
+        let this_move = self
+            .control
+            .move_time
+            .map(|move_time| move_time.saturating_sub(thinking));
+        let in_total = self
+            .control
+            .total_time
+            .map(|total| total.saturating_sub(self.used[slot(team)] + thinking));
+        match (this_move, in_total) {
+            (Some(this_move), Some(in_total)) => Some(this_move.min(in_total)),
+            (left, None) | (None, left) => left,
+        }
+    }
+
+    /// Flags `to_move` if its time ran out by `now`, after `moves` moves, returning whether
+    /// it did.
+    pub fn flag(&mut self, to_move: &Team, moves: usize, now: Instant) -> bool {
+        if self.is_out(to_move) || self.left(to_move, Some(to_move), now) != Some(Duration::ZERO) {
+            return false;
+        }
+        self.time_out(to_move, moves, now);
+        true
+    }
+
+    /// Puts `team` out of the game at `now`, after `moves` moves, as when replaying a game
+    /// where it ran out of time. Whoever plays next starts thinking then.
+    pub fn time_out(&mut self, team: &Team, moves: usize, now: Instant) {
+        if !self.is_out(team) {
+            self.flagged.push(Timeout {
+                team: team.clone(),
+                moves,
+            });
+            self.turn_started = now;
+        }
+    }
+}
+
+/// What [`BoardView`](super::BoardView) shows of a game's clock.
+#[derive(Serialize)]
+pub struct ClockView {
+    /// Seconds per move.
+    move_time: Option<f64>,
+    /// Seconds per team for the whole game.
+    total_time: Option<f64>,
+    /// Seconds left for each team, with the running clock counted down to now.
+    left: Vec<TeamClock>,
+    /// Whether someone's time is running down.
+    running: bool,
+    /// The teams that ran out of time, in the order they did.
+    flagged: Vec<Team>,
+}
+
+#[derive(Serialize)]
+pub struct TeamClock {
+    team: Team,
+    left: Option<f64>,
+}
+
+impl ClockView {
+    pub fn new(clock: &Clock, teams: &[Team], to_move: Option<&Team>) -> Self {
+        let now = Instant::now();
+        Self {
+            move_time: clock.control.move_time.map(|d| d.as_secs_f64()),
+            total_time: clock.control.total_time.map(|d| d.as_secs_f64()),
+            left: teams
+                .iter()
+                .map(|team| TeamClock {
+                    team: team.clone(),
+                    left: clock.left(team, to_move, now).map(|d| d.as_secs_f64()),
+                })
+                .collect(),
+            running: to_move.is_some(),
+            flagged: clock
+                .flagged
+                .iter()
+                .map(|timeout| timeout.team.clone())
+                .collect(),
+        }
+    }
+}
+
+/// Where a team's time goes in [`Clock::used`].
+fn slot(team: &Team) -> usize {
+    match team {
+        Team::Milk => 0,
+        Team::Cookie => 1,
+        Team::Chocolate => 2,
+    }
+}
+
+/// Ends games whose team to move has run out of time, so a player who walks away loses
+/// without anyone having to make a move first.
+pub fn watch_clocks(board_data: Data<BoardData>) {
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(Clock::TICK);
+        tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        loop {
+            tick.tick().await;
+            for (id, game) in board_data.clocked_games() {
+                // A game that's being changed gets looked at again on the next tick
+                let Ok(_saving) = game.saving.try_lock() else {
+                    continue;
+                };
+                let timeout = {
+                    let mut b = game.board.lock().unwrap();
+                    let Some(team) = b.flag(Instant::now()) else {
+                        continue;
+                    };
+                    println!("Game {id}: {team} ran out of time");
+                    board_data.publish(id, || live::update_message(id, "timeout", &b));
+                    b.timeouts().last().cloned()
+                };
+                if let Some(timeout) = timeout {
+                    if let Err(e) = board_data.save_timeout(id, &timeout).await {
+                        println!("Failed to save timeout in game {id}: {e}");
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::day_twelve::{
+        games::replay, Action, Board, GameOptions, Move, MoveError, State, Variant,
+    };
+
+    #[test]
+    fn test_clock() {
+        let options = GameOptions {
+            move_time: Some(10),
+            total_time: Some(15),
+            ..GameOptions::default()
+        };
+        let start = Instant::now();
+        let mut b = options.build().unwrap();
+        assert!(b.strict);
+        let secs = |secs| start + Duration::from_secs(secs);
+
+        // The clock runs from the start, so milk's first move counts too
+        b.play_at(Team::Milk, 1, Action::Drop, secs(5)).unwrap();
+        assert_eq!(b.flag(secs(13)), None);
+        b.play_at(Team::Cookie, 1, Action::Drop, secs(14)).unwrap();
+        b.play_at(Team::Milk, 2, Action::Drop, secs(15)).unwrap();
+        // Cookie has 6 seconds left in total, less than a move's worth
+        assert_eq!(
+            b.play_at(Team::Cookie, 2, Action::Drop, secs(22)),
+            Err(MoveError::GameOver)
+        );
+        assert!(b.winner() == State::Milk);
+        assert_eq!(b.flag(secs(30)), None);
+
+        // Nor can milk hold the game up by never opening
+        let mut b = options.build().unwrap();
+        assert_eq!(
+            b.flag(Instant::now() + Duration::from_secs(11)),
+            Some(Team::Milk)
+        );
+        assert!(b.winner() == State::Cookie);
+
+        let options = GameOptions {
+            total_time: Some(TimeControl::MAX_SECONDS + 1),
+            ..options
+        };
+        assert!(options.build().is_err());
+    }
+
+    #[test]
+    fn test_timeouts() {
+        let options = |variant| GameOptions {
+            variant,
+            move_time: Some(10),
+            ..GameOptions::default()
+        };
+        let start = Instant::now();
+        let secs = |secs| start + Duration::from_secs(secs);
+        let at = |times: &[u64]| times.iter().map(|&t| secs(t)).collect::<Vec<_>>();
+        let moves = |b: &Board, times: &[u64]| -> Vec<(Move, Instant)> {
+            b.history.iter().cloned().zip(at(times)).collect()
+        };
+        let timeouts = |b: &Board, times: &[u64]| -> Vec<(Timeout, Instant)> {
+            b.timeouts().iter().cloned().zip(at(times)).collect()
+        };
+
+        // A game lost on time is still lost when it's replayed
+        let mut b = options(Variant::Classic).build().unwrap();
+        b.play_at(Team::Milk, 1, Action::Drop, secs(5)).unwrap();
+        assert_eq!(b.flag(secs(16)), Some(Team::Cookie));
+        assert!(b.winner() == State::Milk);
+        let mut replayed = options(Variant::Classic).build().unwrap();
+        let illegal = replay(&mut replayed, moves(&b, &[5]), timeouts(&b, &[16]), true);
+        assert!(illegal.is_empty());
+        assert!(replayed.winner() == State::Milk);
+        // Up to the last move, it hadn't happened yet
+        let mut replayed = options(Variant::Classic).build().unwrap();
+        replay(&mut replayed, moves(&b, &[5]), timeouts(&b, &[16]), false);
+        assert!(replayed.winner() == State::Incomplete);
+
+        // With three teams the one out of time loses, and the other two play on
+        let mut b = options(Variant::Three).build().unwrap();
+        b.play_at(Team::Milk, 1, Action::Drop, secs(2)).unwrap();
+        assert_eq!(b.flag(secs(13)), Some(Team::Cookie));
+        assert!(b.winner() == State::Incomplete);
+        assert_eq!(b.next_team(), Some(Team::Chocolate));
+        assert_eq!(
+            b.play_at(Team::Cookie, 2, Action::Drop, secs(14)),
+            Err(MoveError::NotPlaying)
+        );
+        b.play_at(Team::Chocolate, 2, Action::Drop, secs(15))
+            .unwrap();
+        assert_eq!(b.next_team(), Some(Team::Milk));
+        b.play_at(Team::Milk, 3, Action::Drop, secs(16)).unwrap();
+        assert_eq!(b.next_team(), Some(Team::Chocolate));
+        // Until only one is left
+        assert_eq!(b.flag(secs(27)), Some(Team::Chocolate));
+        assert!(b.winner() == State::Milk);
+
+        let mut replayed = options(Variant::Three).build().unwrap();
+        let illegal = replay(
+            &mut replayed,
+            moves(&b, &[2, 15, 16]),
+            timeouts(&b, &[13, 27]),
+            true,
+        );
+        assert!(illegal.is_empty());
+        assert!(replayed.winner() == State::Milk);
+        assert_eq!(replayed.cells(), b.cells());
+    }
+}
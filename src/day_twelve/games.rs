@@ -0,0 +1,509 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::PgPool;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use super::{
+    live::Update, Action, Board, Clock, GameOptions, Move, MoveError, Team, TimeControl, Timeout,
+    Variant,
+};
+
+pub struct Game {
+    pub board: Mutex<Board>,
+    last_used: Mutex<Instant>,
+    /// Whether the server plays the other team after every move.
+    pub auto_reply: AtomicBool,
+    /// Held while a change is saved, so changes reach Postgres in the order they were made.
+    pub(super) saving: tokio::sync::Mutex<()>,
+    /// How the lobby table seated here set the game up, so only its players can change it
+    /// and it restarts the same way.
+    pub seated: Option<GameOptions>,
+}
+
+impl Game {
+    pub(super) fn new(b: Board, seated: Option<GameOptions>) -> Self {
+        Self {
+            board: Mutex::new(b),
+            last_used: Mutex::new(Instant::now()),
+            auto_reply: AtomicBool::new(false),
+            saving: tokio::sync::Mutex::new(()),
+            seated,
+        }
+    }
+}
+
+/// Every game being played, by ID. The plain `/12/...` routes play [`BoardData::DEFAULT_GAME`].
+///
+/// Games are played in memory and saved to the `games` and `game_moves` tables as they go,
+/// so they survive a restart and can be replayed after they've expired.
+pub struct BoardData {
+    games: Mutex<HashMap<Uuid, Arc<Game>>>,
+    pool: PgPool,
+    /// Changes to any game, for the live routes to pick out the ones they follow.
+    updates: broadcast::Sender<Update>,
+}
+
+#[derive(sqlx::FromRow)]
+struct GameRow {
+    id: Uuid,
+    width: i32,
+    height: i32,
+    connect: i32,
+    start: String,
+    strict: bool,
+    first: Option<String>,
+    variant: String,
+    move_time: Option<i32>,
+    total_time: Option<i32>,
+    started_at: DateTime<Utc>,
+}
+
+#[derive(sqlx::FromRow)]
+struct TimeoutRow {
+    team: String,
+    moves: i32,
+    created_at: DateTime<Utc>,
+}
+
+#[derive(sqlx::FromRow, Serialize)]
+pub struct MoveRow {
+    seq: i32,
+    team: String,
+    #[sqlx(rename = "col")]
+    column: i32,
+    action: String,
+    row: Option<i32>,
+    created_at: DateTime<Utc>,
+}
+
+impl BoardData {
+    /// The game behind the plain `/12/...` routes, it never expires.
+    pub const DEFAULT_GAME: Uuid = Uuid::nil();
+    /// How long a game can go untouched before it's thrown away.
+    const IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+    /// Updates a slow client can fall behind by before it skips ahead.
+    const UPDATE_BACKLOG: usize = 64;
+
+    /// Picks up the games that were still being played when the server last stopped.
+    pub async fn load(pool: PgPool) -> Result<Self, sqlx::Error> {
+        let board_data = Self {
+            games: Mutex::new(HashMap::new()),
+            pool,
+            updates: broadcast::channel(Self::UPDATE_BACKLOG).0,
+        };
+
+        let rows: Vec<(Uuid, bool, bool)> = sqlx::query_as(
+            "SELECT id, seated, auto_reply FROM games
+             WHERE id = $1 OR updated_at > now() - make_interval(secs => $2)",
+        )
+        .bind(Self::DEFAULT_GAME)
+        .bind(Self::IDLE_TIMEOUT.as_secs_f64())
+        .fetch_all(&board_data.pool)
+        .await?;
+
+        let mut games = HashMap::new();
+        for (id, seated, auto_reply) in rows {
+            if let Some(b) = board_data.stored_board(id, None).await? {
+                // A lobby game is only ever reset to how it started, so that's its table
+                let seated = seated.then(|| GameOptions::of(&b, false));
+                let game = Game::new(b, seated);
+                game.auto_reply.store(auto_reply, Ordering::Relaxed);
+                games.insert(id, Arc::new(game));
+            }
+        }
+        if let Entry::Vacant(entry) = games.entry(Self::DEFAULT_GAME) {
+            let b = Board::new();
+            board_data.save_start(Self::DEFAULT_GAME, &b).await?;
+            entry.insert(Arc::new(Game::new(b, None)));
+        }
+        println!("loaded {} games", games.len());
+
+        *board_data.games.lock().unwrap() = games;
+        Ok(board_data)
+    }
+
+    /// Games that are never saved, for tests: every query gives up as soon as it's tried.
+    #[cfg(test)]
+    pub(super) fn unsaved() -> Self {
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .acquire_timeout(Duration::from_millis(10))
+            .connect_lazy("postgres://localhost:1/games")
+            .unwrap();
+        let games = HashMap::from([(Self::DEFAULT_GAME, Arc::new(Game::new(Board::new(), None)))]);
+        Self {
+            games: Mutex::new(games),
+            pool,
+            updates: broadcast::channel(Self::UPDATE_BACKLOG).0,
+        }
+    }
+
+    pub async fn create(&self, b: Board, auto_reply: bool) -> Uuid {
+        self.insert(b, auto_reply, None).await
+    }
+
+    /// A new game set up with `options`, that only the players holding its tokens can change.
+    pub async fn create_seated(&self, b: Board, options: GameOptions) -> Uuid {
+        self.insert(b, false, Some(options)).await
+    }
+
+    async fn insert(&self, b: Board, auto_reply: bool, seated: Option<GameOptions>) -> Uuid {
+        let id = Uuid::new_v4();
+        let saved = match self.save_start(id, &b).await {
+            Ok(()) => sqlx::query("UPDATE games SET seated = $2, auto_reply = $3 WHERE id = $1")
+                .bind(id)
+                .bind(seated.is_some())
+                .bind(auto_reply)
+                .execute(&self.pool)
+                .await
+                .map(|_| ()),
+            saved => saved,
+        };
+        if let Err(e) = saved {
+            println!("Failed to save game {id}: {e}");
+        }
+
+        let mut games = self.games.lock().unwrap();
+        Self::expire(&mut games);
+        let game = Game::new(b, seated);
+        game.auto_reply.store(auto_reply, Ordering::Relaxed);
+        games.insert(id, Arc::new(game));
+        id
+    }
+
+    pub fn game(&self, id: Uuid) -> Option<Arc<Game>> {
+        let mut games = self.games.lock().unwrap();
+        Self::expire(&mut games);
+        let game = games.get(&id)?.clone();
+        *game.last_used.lock().unwrap() = Instant::now();
+        Some(game)
+    }
+
+    /// The games being played against the clock, without counting as a use of them.
+    pub(super) fn clocked_games(&self) -> Vec<(Uuid, Arc<Game>)> {
+        let games = self.games.lock().unwrap();
+        games
+            .iter()
+            .filter(|(_, game)| game.board.lock().unwrap().clock.is_some())
+            .map(|(&id, game)| (id, game.clone()))
+            .collect()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Update> {
+        self.updates.subscribe()
+    }
+
+    /// Sends a change to everyone following along, only rendering it if someone is.
+    pub(super) fn publish(&self, id: Uuid, message: impl FnOnce() -> String) {
+        if self.updates.receiver_count() > 0 {
+            // Sending only fails once the last receiver has gone
+            let _ = self.updates.send(Update {
+                game: id,
+                message: message(),
+            });
+        }
+    }
+
+    fn expire(games: &mut HashMap<Uuid, Arc<Game>>) {
+        games.retain(|&id, game| {
+            id == Self::DEFAULT_GAME
+                || game.last_used.lock().unwrap().elapsed() < Self::IDLE_TIMEOUT
+        });
+    }
+
+    /// Records whether the server plays the other team in the game, for when it's loaded again.
+    pub(super) async fn save_auto_reply(
+        &self,
+        id: Uuid,
+        auto_reply: bool,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE games SET auto_reply = $2 WHERE id = $1")
+            .bind(id)
+            .bind(auto_reply)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Records `b` as the position a game starts from, forgetting any moves made before.
+    pub(super) async fn save_start(&self, id: Uuid, b: &Board) -> Result<(), sqlx::Error> {
+        let control = b.clock.as_ref().map(|clock| clock.control);
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "INSERT INTO games
+                (id, width, height, connect, start, strict, first, variant, move_time, total_time)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+             ON CONFLICT (id) DO UPDATE SET
+                width = EXCLUDED.width,
+                height = EXCLUDED.height,
+                connect = EXCLUDED.connect,
+                start = EXCLUDED.start,
+                strict = EXCLUDED.strict,
+                first = EXCLUDED.first,
+                variant = EXCLUDED.variant,
+                move_time = EXCLUDED.move_time,
+                total_time = EXCLUDED.total_time,
+                started_at = now(),
+                updated_at = now()",
+        )
+        .bind(id)
+        .bind(b.width as i32)
+        .bind(b.height as i32)
+        .bind(b.connect as i32)
+        .bind(b.cells())
+        .bind(b.strict)
+        .bind(b.first.as_ref().map(Team::as_str))
+        .bind(b.variant.as_str())
+        .bind(
+            control
+                .and_then(|control| control.move_time)
+                .map(|d| d.as_secs() as i32),
+        )
+        .bind(
+            control
+                .and_then(|control| control.total_time)
+                .map(|d| d.as_secs() as i32),
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("DELETE FROM game_moves WHERE game_id = $1")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM game_timeouts WHERE game_id = $1")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await
+    }
+
+    /// Appends the `seq`th move (counting from 1) to a game's history.
+    pub(super) async fn save_move(
+        &self,
+        id: Uuid,
+        seq: usize,
+        m: &Move,
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "INSERT INTO game_moves (game_id, seq, team, col, action, row)
+             VALUES ($1, $2, $3, $4, $5, $6)",
+        )
+        .bind(id)
+        .bind(seq as i32)
+        .bind(m.team.as_str())
+        .bind(m.column as i32)
+        .bind(m.action.as_str())
+        .bind(m.action.row().map(i32::from))
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("UPDATE games SET updated_at = now() WHERE id = $1")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await
+    }
+
+    /// Records a team running out of time, so it's still out when the game's loaded again.
+    pub(super) async fn save_timeout(
+        &self,
+        id: Uuid,
+        timeout: &Timeout,
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "INSERT INTO game_timeouts (game_id, team, moves) VALUES ($1, $2, $3)
+             ON CONFLICT DO NOTHING",
+        )
+        .bind(id)
+        .bind(timeout.team.as_str())
+        .bind(timeout.moves as i32)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("UPDATE games SET updated_at = now() WHERE id = $1")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await
+    }
+
+    /// Removes the `seq`th move, after it's been taken back.
+    pub(super) async fn delete_move(&self, id: Uuid, seq: usize) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM game_moves WHERE game_id = $1 AND seq = $2")
+            .bind(id)
+            .bind(seq as i32)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// The saved moves of a game, or `None` if there's no such game.
+    pub async fn moves(&self, id: Uuid) -> Result<Option<Vec<MoveRow>>, sqlx::Error> {
+        let exists: Option<Uuid> = sqlx::query_scalar("SELECT id FROM games WHERE id = $1")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+        if exists.is_none() {
+            return Ok(None);
+        }
+
+        let moves = sqlx::query_as(
+            "SELECT seq, team, col, action, row, created_at FROM game_moves
+             WHERE game_id = $1 ORDER BY seq",
+        )
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(Some(moves))
+    }
+
+    /// Rebuilds a game from Postgres, stopping after the first `upto` moves if given.
+    pub async fn stored_board(
+        &self,
+        id: Uuid,
+        upto: Option<usize>,
+    ) -> Result<Option<Board>, sqlx::Error> {
+        let Some(row): Option<GameRow> = sqlx::query_as(
+            "SELECT id, width, height, connect, start, strict, first, variant, move_time, total_time,
+                started_at
+             FROM games WHERE id = $1",
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?
+        else {
+            return Ok(None);
+        };
+
+        let mut b = Board::with_size(
+            row.width as usize,
+            row.height as usize,
+            row.connect as usize,
+        );
+        b.strict = row.strict;
+        b.first = row.first.as_deref().and_then(Team::parse);
+        b.variant = Variant::parse(&row.variant).unwrap_or_else(|| {
+            println!("Game {} has unknown variant {}", row.id, row.variant);
+            Variant::Classic
+        });
+        // Clocks are rebuilt from when the game started and the moves were saved
+        let (now, now_utc) = (Instant::now(), Utc::now());
+        let at = |time: DateTime<Utc>| {
+            let ago = (now_utc - time).to_std().unwrap_or_default();
+            now.checked_sub(ago).unwrap_or(now)
+        };
+        if row.move_time.is_some() || row.total_time.is_some() {
+            let secs = |secs: Option<i32>| secs.map(|secs| Duration::from_secs(secs as u64));
+            let control = TimeControl {
+                move_time: secs(row.move_time),
+                total_time: secs(row.total_time),
+            };
+            b.clock = Some(Clock::new(control, at(row.started_at)));
+        }
+        if let Err(e) = b.set_cells(&row.start) {
+            println!("Game {} has a broken start position: {e}", row.id);
+        }
+
+        let rows: Vec<MoveRow> = sqlx::query_as(
+            "SELECT seq, team, col, action, row, created_at FROM game_moves
+             WHERE game_id = $1 ORDER BY seq LIMIT $2",
+        )
+        .bind(id)
+        .bind(upto.map(|n| i64::try_from(n).unwrap_or(i64::MAX)))
+        .fetch_all(&self.pool)
+        .await?;
+        // Every saved move was asked for, so the timeouts after the last one happened too
+        let whole_game = upto.is_none_or(|upto| rows.len() < upto);
+        let mut moves = vec![];
+        for m in rows {
+            let Some(team) = Team::parse(&m.team) else {
+                println!("Game {} has a move by unknown team {}", row.id, m.team);
+                continue;
+            };
+            let Some(action) = Action::parse(&m.action, m.row.map(|row| row as u8)) else {
+                println!(
+                    "Game {} has a move with unknown action {}",
+                    row.id, m.action
+                );
+                continue;
+            };
+            let played = Move {
+                team,
+                column: m.column as u8,
+                action,
+            };
+            moves.push((played, at(m.created_at)));
+        }
+
+        let rows: Vec<TimeoutRow> = sqlx::query_as(
+            "SELECT team, moves, created_at FROM game_timeouts
+             WHERE game_id = $1 ORDER BY moves, created_at",
+        )
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await?;
+        let mut timeouts = vec![];
+        for t in rows {
+            let Some(team) = Team::parse(&t.team) else {
+                println!("Game {} has a timeout for unknown team {}", row.id, t.team);
+                continue;
+            };
+            let timeout = Timeout {
+                team,
+                moves: t.moves as usize,
+            };
+            timeouts.push((timeout, at(t.created_at)));
+        }
+
+        for (m, e) in replay(&mut b, moves, timeouts, whole_game) {
+            println!(
+                "Game {} has an illegal move by {} in column {}: {e}",
+                row.id, m.team, m.column
+            );
+        }
+
+        Ok(Some(b))
+    }
+}
+
+/// Plays `moves` on `b`, putting each team that ran out of time out of the game before the
+/// first move made after it did. Timeouts after the last move only count when `moves` are
+/// the `whole_game`. Gives back the moves that couldn't be played.
+pub(super) fn replay(
+    b: &mut Board,
+    moves: Vec<(Move, Instant)>,
+    timeouts: Vec<(Timeout, Instant)>,
+    whole_game: bool,
+) -> Vec<(Move, MoveError)> {
+    let time_out = |b: &mut Board, (timeout, at): (Timeout, Instant)| {
+        if let Some(clock) = &mut b.clock {
+            clock.time_out(&timeout.team, timeout.moves, at);
+        }
+    };
+
+    let mut timeouts = timeouts.into_iter().peekable();
+    let mut illegal = vec![];
+    for (m, at) in moves {
+        while let Some(t) = timeouts.next_if(|(t, _)| t.moves <= b.history.len()) {
+            time_out(b, t);
+        }
+        if let Err(e) = b.play_at(m.team.clone(), m.column, m.action, at) {
+            illegal.push((m, e));
+        }
+    }
+    if whole_game {
+        for t in timeouts {
+            time_out(b, t);
+        }
+    }
+    illegal
+}
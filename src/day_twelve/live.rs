@@ -0,0 +1,223 @@
+use std::convert::Infallible;
+
+use actix_web::{
+    body, get,
+    http::header::{CacheControl, CacheDirective},
+    web::{self, Bytes, Data, Path},
+    HttpRequest, HttpResponse, Responder,
+};
+use actix_ws::Message;
+use futures_util::{
+    stream::{once, unfold},
+    StreamExt,
+};
+use serde::Deserialize;
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use uuid::Uuid;
+
+use super::{lobby, place_in, reject, Action, Board, BoardData, BoardView, Team};
+
+/// A change to a game, already rendered for the clients following it.
+#[derive(Clone)]
+pub struct Update {
+    pub game: Uuid,
+    pub message: String,
+}
+
+/// The JSON pushed to `/12/stream` and `/12/ws` clients, naming what happened and the board
+/// it left behind.
+pub(super) fn update_message(id: Uuid, event: &str, b: &Board) -> String {
+    serde_json::json!({
+        "game": id,
+        "event": event,
+        "board": BoardView::new(b),
+    })
+    .to_string()
+}
+
+/// Waits for the next change to game `id`, or `None` once the server stops publishing.
+async fn next_update(updates: &mut Receiver<Update>, id: Uuid) -> Option<String> {
+    loop {
+        match updates.recv().await {
+            Ok(update) if update.game == id => return Some(update.message),
+            // Every update carries the whole board, so a slow client only needs the next one
+            Ok(_) | Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+#[get("/12/stream")]
+pub async fn stream(board_data: Data<BoardData>) -> impl Responder {
+    stream_in(&board_data, BoardData::DEFAULT_GAME)
+}
+
+#[get("/12/games/{id}/stream")]
+pub async fn game_stream(id: Path<Uuid>, board_data: Data<BoardData>) -> impl Responder {
+    stream_in(&board_data, id.into_inner())
+}
+
+#[get("/12/ws")]
+pub async fn ws(
+    req: HttpRequest,
+    payload: web::Payload,
+    board_data: Data<BoardData>,
+) -> actix_web::Result<HttpResponse> {
+    ws_in(&req, payload, board_data, BoardData::DEFAULT_GAME)
+}
+
+#[get("/12/games/{id}/ws")]
+pub async fn game_ws(
+    id: Path<Uuid>,
+    req: HttpRequest,
+    payload: web::Payload,
+    board_data: Data<BoardData>,
+) -> actix_web::Result<HttpResponse> {
+    ws_in(&req, payload, board_data, id.into_inner())
+}
+
+/// Server-Sent Events with the board as it is now, then again after every change.
+fn stream_in(board_data: &BoardData, id: Uuid) -> HttpResponse {
+    let Some(game) = board_data.game(id) else {
+        return HttpResponse::NotFound().finish();
+    };
+    // Subscribe first so nothing slips in between the current board and the first update
+    let updates = board_data.subscribe();
+    let current = update_message(id, "board", &game.board.lock().unwrap());
+
+    let events = once(async { current })
+        .chain(unfold(updates, move |mut updates| async move {
+            let message = next_update(&mut updates, id).await?;
+            Some((message, updates))
+        }))
+        .map(|message| Ok::<_, Infallible>(Bytes::from(format!("data: {message}\n\n"))));
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(CacheControl(vec![CacheDirective::NoCache]))
+        .streaming(events)
+}
+
+/// A move sent by a WebSocket client, e.g. `{"team": "milk", "column": 3}`. Pop Out moves
+/// add `"pop": true` and no-gravity ones the `"row"`.
+#[derive(Deserialize)]
+struct WsMove {
+    team: Team,
+    column: u8,
+    #[serde(default)]
+    pop: bool,
+    row: Option<u8>,
+}
+
+impl WsMove {
+    fn action(&self) -> Action {
+        match (self.pop, self.row) {
+            (true, _) => Action::Pop,
+            (false, Some(row)) => Action::Put { row },
+            (false, None) => Action::Drop,
+        }
+    }
+}
+
+/// Pushes the same updates as [`stream_in`] and plays the moves the client sends. Moves that
+/// are turned down get the JSON error from `/12/place` back, on this socket only. In lobby
+/// games the player token sent with the handshake says which team the client plays.
+fn ws_in(
+    req: &HttpRequest,
+    payload: web::Payload,
+    board_data: Data<BoardData>,
+    id: Uuid,
+) -> actix_web::Result<HttpResponse> {
+    let Some(game) = board_data.game(id) else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+    let seat = lobby::player(id, req);
+    let (res, mut session, mut messages) = actix_ws::handle(req, payload)?;
+    let mut updates = board_data.subscribe();
+    let current = update_message(id, "board", &game.board.lock().unwrap());
+
+    let mut outgoing = session.clone();
+    let forward = actix_web::rt::spawn(async move {
+        if outgoing.text(current).await.is_err() {
+            return;
+        }
+        while let Some(message) = next_update(&mut updates, id).await {
+            if outgoing.text(message).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    actix_web::rt::spawn(async move {
+        while let Some(Ok(message)) = messages.recv().await {
+            let sent = match message {
+                Message::Text(text) => match serde_json::from_str::<WsMove>(&text) {
+                    Ok(m) => {
+                        let action = m.action();
+                        let res = match lobby::check_seat(&game, seat.as_ref(), Some(&m.team)) {
+                            Ok(()) => {
+                                place_in(&board_data, id, m.team, m.column, action, true).await
+                            }
+                            Err(e) => {
+                                let b = game.board.lock().unwrap();
+                                reject(&e, &b, true)
+                            }
+                        };
+                        // Successful moves reach this client through the broadcast like any other
+                        if res.status().is_success() {
+                            continue;
+                        }
+                        let body = body::to_bytes(res.into_body()).await.unwrap_or_default();
+                        session
+                            .text(String::from_utf8_lossy(&body).into_owned())
+                            .await
+                    }
+                    Err(e) => {
+                        let error = serde_json::json!({
+                            "error": "bad_message",
+                            "reason": e.to_string(),
+                        });
+                        session.text(error.to_string()).await
+                    }
+                },
+                Message::Ping(bytes) => session.pong(&bytes).await,
+                Message::Close(reason) => {
+                    let _ = session.close(reason).await;
+                    break;
+                }
+                _ => Ok(()),
+            };
+            if sent.is_err() {
+                break;
+            }
+        }
+        forward.abort();
+    });
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_move_is_broadcast() {
+        let board_data = BoardData::unsaved();
+        let id = board_data.create(Board::new(), false).await;
+        let mut updates = board_data.subscribe();
+
+        // A move in another game isn't sent to this one's followers
+        let default = BoardData::DEFAULT_GAME;
+        place_in(&board_data, default, Team::Milk, 1, Action::Drop, true).await;
+        let res = place_in(&board_data, id, Team::Cookie, 2, Action::Drop, true).await;
+        assert!(res.status().is_success());
+
+        let message = next_update(&mut updates, id).await.unwrap();
+        let update: serde_json::Value = serde_json::from_str(&message).unwrap();
+        assert_eq!(update["game"], id.to_string());
+        assert_eq!(update["event"], "place");
+        assert_eq!(update["board"]["grid"][3][1], "cookie");
+        assert_eq!(update["board"]["grid"][3][0], serde_json::Value::Null);
+    }
+}
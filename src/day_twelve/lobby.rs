@@ -0,0 +1,313 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    cookie::Cookie,
+    get,
+    http::header,
+    post,
+    web::{Data, Path, Query},
+    HttpRequest, HttpResponse, Responder,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::{Board, BoardData, Game, GameOptions, MoveError, Team};
+use crate::day_sixteen;
+
+/// Players waiting for a game, and the seats of the ones who've got one.
+#[derive(Default)]
+pub struct Lobby {
+    state: Mutex<LobbyState>,
+}
+
+#[derive(Default)]
+struct LobbyState {
+    /// Games still short of players, oldest first, and full ones still being started.
+    tables: Vec<Table>,
+    /// Where every seated ticket ended up.
+    seats: HashMap<Uuid, (Seat, Instant)>,
+}
+
+/// Players waiting to play the same kind of game.
+struct Table {
+    options: GameOptions,
+    tickets: Vec<Uuid>,
+    opened: Instant,
+    /// Full, and waiting for its game to be saved. Its tickets stay valid until they're seated.
+    starting: bool,
+}
+
+/// A player's place in a lobby game.
+#[derive(Clone, Serialize)]
+pub struct Seat {
+    game: Uuid,
+    team: Team,
+    /// Sent back as a bearer token, or the `player` cookie, to play as `team`.
+    token: String,
+}
+
+/// What a player token says about its holder.
+#[derive(Serialize, Deserialize)]
+struct PlayerClaims {
+    /// Always [`Lobby::AUDIENCE`], so a gift signed with the same key can't pass for a seat.
+    aud: String,
+    game: Uuid,
+    team: Team,
+    /// Seconds since the epoch.
+    exp: u64,
+}
+
+impl Lobby {
+    /// How long a player waits for others before the ticket is dropped, and how long a seat
+    /// can be picked up after the game's started.
+    const TICKET_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+    /// Who player tokens are meant for.
+    const AUDIENCE: &str = "12/player";
+    /// How long a player token lasts.
+    const TOKEN_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn expire(state: &mut LobbyState) {
+        state
+            .tables
+            .retain(|table| table.starting || table.opened.elapsed() < Self::TICKET_TIMEOUT);
+        state
+            .seats
+            .retain(|_, (_, seated)| seated.elapsed() < Self::TICKET_TIMEOUT);
+    }
+}
+
+/// Joins the first table waiting for the same kind of game, or opens a new one. Whoever fills
+/// the table gets a seat straight away, the others pick theirs up with the ticket.
+#[post("/12/lobby")]
+pub async fn join_lobby(
+    options: Query<GameOptions>,
+    lobby: Data<Lobby>,
+    board_data: Data<BoardData>,
+) -> impl Responder {
+    let options = options.into_inner();
+    if let Err(e) = options.build() {
+        return HttpResponse::BadRequest().body(e);
+    }
+
+    let ticket = Uuid::new_v4();
+    let full = {
+        let mut state = lobby.state.lock().unwrap();
+        Lobby::expire(&mut state);
+        let i = match state
+            .tables
+            .iter()
+            .position(|table| !table.starting && table.options.same_game(&options))
+        {
+            Some(i) => i,
+            None => {
+                state.tables.push(Table {
+                    options: options.clone(),
+                    tickets: vec![],
+                    opened: Instant::now(),
+                    starting: false,
+                });
+                state.tables.len() - 1
+            }
+        };
+        let table = &mut state.tables[i];
+        table.tickets.push(ticket);
+        table.starting = table.tickets.len() == options.variant.teams().len();
+        table
+            .starting
+            .then(|| (table.options.clone(), table.tickets.clone()))
+    };
+    let Some((options, tickets)) = full else {
+        return HttpResponse::Accepted().json(serde_json::json!({ "ticket": ticket }));
+    };
+
+    let seats = seat(&board_data, &options, tickets).await;
+    // The table goes once its seats are there to pick up, so no ticket is ever lost
+    let mut state = lobby.state.lock().unwrap();
+    state
+        .tables
+        .retain(|table| !table.tickets.contains(&ticket));
+    for (ticket, seat) in &seats {
+        state.seats.insert(*ticket, (seat.clone(), Instant::now()));
+    }
+    let seat = state.seats[&ticket].0.clone();
+    seated(seat)
+}
+
+/// A waiting player's seat, once the table's full.
+#[get("/12/lobby/{ticket}")]
+pub async fn lobby_ticket(ticket: Path<Uuid>, lobby: Data<Lobby>) -> impl Responder {
+    let ticket = ticket.into_inner();
+    let mut state = lobby.state.lock().unwrap();
+    Lobby::expire(&mut state);
+    if let Some((seat, _)) = state.seats.get(&ticket) {
+        return seated(seat.clone());
+    }
+    if state
+        .tables
+        .iter()
+        .any(|table| table.tickets.contains(&ticket))
+    {
+        return HttpResponse::Accepted().json(serde_json::json!({ "ticket": ticket }));
+    }
+    HttpResponse::NotFound().finish()
+}
+
+/// Starts a strict game for a full table, handing out the teams in the order players joined.
+async fn seat(
+    board_data: &BoardData,
+    options: &GameOptions,
+    tickets: Vec<Uuid>,
+) -> Vec<(Uuid, Seat)> {
+    // Players take turns against each other, never the server
+    let options = GameOptions {
+        strict: true,
+        opponent: false,
+        ..options.clone()
+    };
+    let b = table_board(&options);
+    let teams = b.variant.teams();
+    let game = board_data.create_seated(b, options).await;
+    println!("Lobby started game {game} for {} players", teams.len());
+
+    let exp = jsonwebtoken::get_current_timestamp() + Lobby::TOKEN_LIFETIME.as_secs();
+    tickets
+        .into_iter()
+        .zip(teams)
+        .map(|(ticket, team)| {
+            let claims = PlayerClaims {
+                aud: Lobby::AUDIENCE.to_string(),
+                game,
+                team: team.clone(),
+                exp,
+            };
+            let token = day_sixteen::sign(&claims);
+            let seat = Seat {
+                game,
+                team: team.clone(),
+                token,
+            };
+            (ticket, seat)
+        })
+        .collect()
+}
+
+/// A fresh board for a lobby game set up with `options`, milk to move first.
+pub(super) fn table_board(options: &GameOptions) -> Board {
+    let mut b = options.build().expect("checked when the players joined");
+    b.strict = true;
+    b.first = Some(Team::Milk);
+    b
+}
+
+fn seated(seat: Seat) -> HttpResponse {
+    // Scoped to the game, so seats in different games don't overwrite each other
+    let cookie = Cookie::build("player", seat.token.clone())
+        .path(format!("/12/games/{}", seat.game))
+        .http_only(true)
+        .finish();
+    HttpResponse::Ok().cookie(cookie).json(seat)
+}
+
+/// The team a request's player token seats it at in game `id`, if it has a valid one.
+pub(super) fn player(id: Uuid, req: &HttpRequest) -> Option<Team> {
+    let bearer = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::to_string);
+    let jwt = bearer.or_else(|| req.cookie("player").map(|c| c.value().to_string()))?;
+    let claims: PlayerClaims = day_sixteen::verify(&jwt, Some(Lobby::AUDIENCE))?;
+    (claims.game == id).then_some(claims.team)
+}
+
+/// Lets a change through if the game's open to all, or the player has a seat there. A move
+/// for `team` needs that team's seat.
+pub(super) fn check_seat(
+    game: &Game,
+    seat: Option<&Team>,
+    team: Option<&Team>,
+) -> Result<(), MoveError> {
+    if game.seated.is_none() {
+        return Ok(());
+    }
+    let Some(seat) = seat else {
+        return Err(MoveError::Unseated);
+    };
+    match team {
+        Some(team) if team != seat => Err(MoveError::WrongSeat(seat.clone())),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    #[test]
+    fn test_check_seat() {
+        let open = Game::new(Board::new(), None);
+        assert_eq!(check_seat(&open, None, Some(&Team::Milk)), Ok(()));
+
+        let options = GameOptions {
+            strict: true,
+            ..GameOptions::default()
+        };
+        let seated = Game::new(table_board(&options), Some(options));
+        assert_eq!(
+            check_seat(&seated, None, Some(&Team::Milk)),
+            Err(MoveError::Unseated)
+        );
+        assert_eq!(
+            check_seat(&seated, Some(&Team::Cookie), Some(&Team::Milk)),
+            Err(MoveError::WrongSeat(Team::Cookie))
+        );
+        assert_eq!(
+            check_seat(&seated, Some(&Team::Milk), Some(&Team::Milk)),
+            Ok(())
+        );
+        // Changes that aren't a team's move only need a seat
+        assert_eq!(check_seat(&seated, Some(&Team::Cookie), None), Ok(()));
+    }
+
+    #[test]
+    fn test_player_token() {
+        let game = Uuid::new_v4();
+        let exp = jsonwebtoken::get_current_timestamp() + 60;
+        let request = |token: &str| {
+            TestRequest::default()
+                .insert_header((header::AUTHORIZATION, format!("Bearer {token}")))
+                .to_http_request()
+        };
+
+        let token = day_sixteen::sign(&PlayerClaims {
+            aud: Lobby::AUDIENCE.to_string(),
+            game,
+            team: Team::Cookie,
+            exp,
+        });
+        assert_eq!(player(game, &request(&token)), Some(Team::Cookie));
+        assert_eq!(player(Uuid::new_v4(), &request(&token)), None);
+
+        // The same claims signed with the same key, but meant for something else
+        let elsewhere = day_sixteen::sign(&PlayerClaims {
+            aud: "16/gift".to_string(),
+            game,
+            team: Team::Cookie,
+            exp,
+        });
+        assert_eq!(player(game, &request(&elsewhere)), None);
+        let gift = day_sixteen::sign(&serde_json::json!({ "game": game, "team": "cookie" }));
+        assert_eq!(player(game, &request(&gift)), None);
+    }
+}
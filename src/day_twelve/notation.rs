@@ -0,0 +1,190 @@
+use std::str::FromStr;
+
+use super::{Board, Team, Tile, Variant};
+
+const WALL: char = '⬜';
+
+impl Board {
+    /// A compact text form of the position, e.g. `..../..../.c../mm.. c 4`: the rows from the
+    /// top separated by `/`, the team to move (`m`, `c`, `h` or `-` for anyone) and how many
+    /// in a row win, then the variant unless it's classic.
+    pub fn notation(&self) -> String {
+        let rows: Vec<String> = self
+            .board
+            .chunks(self.width)
+            .map(|row| row.iter().map(Tile::to_char).collect())
+            .collect();
+        let to_move = self
+            .next_team()
+            .map_or('-', |team| team.to_tile().to_char());
+        let mut notation = format!("{} {to_move} {}", rows.join("/"), self.connect);
+        if self.variant != Variant::Classic {
+            notation = format!("{notation} {}", self.variant.as_str());
+        }
+        notation
+    }
+
+    /// Reads the grid printed by `Display`, walls and all. The winner line, if any, is ignored.
+    /// Chocolate on the board makes it a three-team game.
+    fn from_emoji(s: &str) -> Result<Board, String> {
+        let mut rows = vec![];
+        for line in s.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            let Some(inner) = line
+                .strip_prefix(WALL)
+                .and_then(|line| line.strip_suffix(WALL))
+            else {
+                // Past the bottom wall there's only the result
+                break;
+            };
+            if inner.chars().all(|c| c == WALL) {
+                break;
+            }
+            let row = inner
+                .chars()
+                .map(|c| match c {
+                    '⬛' => Ok('.'),
+                    '🥛' => Ok('m'),
+                    '🍪' => Ok('c'),
+                    '🍫' => Ok('h'),
+                    _ => Err(format!("unknown cell {c:?}")),
+                })
+                .collect::<Result<String, _>>()?;
+            rows.push(row);
+        }
+
+        let longest = rows
+            .iter()
+            .map(|row| row.chars().count())
+            .max()
+            .unwrap_or(0);
+        let connect = Board::DEFAULT_SIZE.min(longest.max(rows.len()));
+        let variant = if rows.iter().any(|row| row.contains('h')) {
+            Variant::Three
+        } else {
+            Variant::Classic
+        };
+        Board::from_rows(&rows, None, connect, variant)
+    }
+
+    fn from_rows(
+        rows: &[String],
+        first: Option<Team>,
+        connect: usize,
+        variant: Variant,
+    ) -> Result<Board, String> {
+        let width = rows.first().map_or(0, |row| row.chars().count());
+        let height = rows.len();
+        if !(1..=Board::MAX_SIZE).contains(&width) || !(1..=Board::MAX_SIZE).contains(&height) {
+            return Err(format!(
+                "width and height must be between 1 and {}",
+                Board::MAX_SIZE
+            ));
+        }
+        if rows.iter().any(|row| row.chars().count() != width) {
+            return Err("rows must all be the same length".to_string());
+        }
+        if connect == 0 || connect > width.max(height) {
+            return Err(format!(
+                "connect must be between 1 and {}",
+                width.max(height)
+            ));
+        }
+
+        let mut b = Board::with_size(width, height, connect);
+        b.variant = variant;
+        b.set_cells(&rows.concat())?;
+        let playing = |tile: &Tile| {
+            tile.team()
+                .is_none_or(|team| variant.teams().contains(&team))
+        };
+        if !b.board.iter().all(playing)
+            || !first
+                .as_ref()
+                .is_none_or(|team| variant.teams().contains(team))
+        {
+            return Err(format!(
+                "only {} games have chocolate",
+                Variant::Three.as_str()
+            ));
+        }
+        // Pieces fall, so nothing can sit on top of an empty cell
+        let floating = (width..b.board.len())
+            .any(|i| b.board[i] == Tile::Empty && b.board[i - width] != Tile::Empty);
+        if floating && variant != Variant::NoGravity {
+            return Err("pieces can't float above empty cells".to_string());
+        }
+        b.first = first;
+        Ok(b)
+    }
+}
+
+impl FromStr for Board {
+    type Err = String;
+
+    /// Either the notation from [`Board::notation`] or the emoji grid from `Display`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.contains(WALL) {
+            return Board::from_emoji(s);
+        }
+
+        let mut fields = s.split_whitespace();
+        let rows: Vec<String> = fields
+            .next()
+            .ok_or("empty position")?
+            .split('/')
+            .map(str::to_string)
+            .collect();
+        let first = match fields.next() {
+            None | Some("-") => None,
+            Some("m") => Some(Team::Milk),
+            Some("c") => Some(Team::Cookie),
+            Some("h") => Some(Team::Chocolate),
+            Some(side) => return Err(format!("unknown team to move {side:?}")),
+        };
+        let connect = match fields.next() {
+            Some(connect) => connect
+                .parse()
+                .map_err(|_| format!("bad connect {connect:?}"))?,
+            None => Board::DEFAULT_SIZE.min(rows.len().max(rows[0].chars().count())),
+        };
+        let variant = match fields.next() {
+            Some(variant) => {
+                Variant::parse(variant).ok_or_else(|| format!("unknown variant {variant:?}"))?
+            }
+            None => Variant::Classic,
+        };
+        if let Some(extra) = fields.next() {
+            return Err(format!("unexpected {extra:?}"));
+        }
+
+        Board::from_rows(&rows, first, connect, variant)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_notation() {
+        let mut b = Board::with_size(4, 3, 3);
+        b.place(Team::Milk, 1).unwrap();
+        b.place(Team::Cookie, 1).unwrap();
+        b.place(Team::Milk, 2).unwrap();
+        assert_eq!(b.notation(), "..../c.../mm.. c 3");
+
+        let copy: Board = b.notation().parse().unwrap();
+        assert_eq!(copy.cells(), b.cells());
+        assert_eq!(copy.connect, 3);
+        assert_eq!(copy.next_team(), Some(Team::Cookie));
+
+        let copy: Board = b.to_string().parse().unwrap();
+        assert_eq!(copy.cells(), b.cells());
+        assert_eq!(copy.next_team(), None);
+
+        assert!("..../m.../.... - 3".parse::<Board>().is_err());
+        assert!("..../.../.... - 3".parse::<Board>().is_err());
+        assert!("..../..../.... x".parse::<Board>().is_err());
+        assert!("..../..../.... - 5".parse::<Board>().is_err());
+    }
+}
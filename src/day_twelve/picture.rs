@@ -0,0 +1,156 @@
+use actix_web::{
+    get,
+    web::{Data, Path},
+    HttpResponse, Responder,
+};
+use indoc::formatdoc;
+use resvg::{tiny_skia, usvg};
+use uuid::Uuid;
+
+use super::{Board, BoardData, Team};
+
+/// Pixels per cell, walls included.
+const CELL: usize = 40;
+const WALL: &str = "#555";
+const HOLE: &str = "#1a1a1a";
+const GOLD: &str = "darkgoldenrod";
+
+#[get("/12/board.svg")]
+pub async fn board_svg(board_data: Data<BoardData>) -> impl Responder {
+    svg_in(&board_data, BoardData::DEFAULT_GAME)
+}
+
+#[get("/12/board.png")]
+pub async fn board_png(board_data: Data<BoardData>) -> impl Responder {
+    png_in(&board_data, BoardData::DEFAULT_GAME)
+}
+
+#[get("/12/games/{id}/board.svg")]
+pub async fn game_board_svg(id: Path<Uuid>, board_data: Data<BoardData>) -> impl Responder {
+    svg_in(&board_data, id.into_inner())
+}
+
+#[get("/12/games/{id}/board.png")]
+pub async fn game_board_png(id: Path<Uuid>, board_data: Data<BoardData>) -> impl Responder {
+    png_in(&board_data, id.into_inner())
+}
+
+fn svg_in(board_data: &BoardData, id: Uuid) -> HttpResponse {
+    let Some(game) = board_data.game(id) else {
+        return HttpResponse::NotFound().finish();
+    };
+    let svg = svg(&game.board.lock().unwrap());
+    HttpResponse::Ok().content_type("image/svg+xml").body(svg)
+}
+
+fn png_in(board_data: &BoardData, id: Uuid) -> HttpResponse {
+    let Some(game) = board_data.game(id) else {
+        return HttpResponse::NotFound().finish();
+    };
+    let svg = svg(&game.board.lock().unwrap());
+    match png(&svg) {
+        Ok(png) => HttpResponse::Ok().content_type("image/png").body(png),
+        Err(e) => {
+            println!("Failed to render game {id} as a PNG: {e}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// The board as an SVG, laid out like the emoji board: walls down the sides and along the
+/// bottom, a disc for every piece and a gold line through the winning cells.
+pub fn svg(b: &Board) -> String {
+    let (width, height) = ((b.width + 2) * CELL, (b.height + 1) * CELL);
+    let centre = |i: usize| {
+        (
+            (i % b.width + 1) * CELL + CELL / 2,
+            (i / b.width) * CELL + CELL / 2,
+        )
+    };
+    let radius = CELL * 2 / 5;
+
+    let mut shapes = vec![];
+    for y in 0..b.height {
+        for x in [0, b.width + 1] {
+            shapes.push(wall(x, y));
+        }
+    }
+    for x in 0..b.width + 2 {
+        shapes.push(wall(x, b.height));
+    }
+
+    for (i, tile) in b.board.iter().enumerate() {
+        let (cx, cy) = centre(i);
+        let (fill, stroke) = match tile.team() {
+            Some(Team::Milk) => ("#f4f4f4", "#c8d0d8"),
+            Some(Team::Cookie) => ("#d29a5a", "#8a5a2b"),
+            Some(Team::Chocolate) => ("#5b3419", "#3a1f0d"),
+            None => (HOLE, HOLE),
+        };
+        shapes.push(format!(
+            r#"<circle cx="{cx}" cy="{cy}" r="{radius}" fill="{fill}" stroke="{stroke}" stroke-width="3"/>"#
+        ));
+    }
+
+    if let Some(line) = b.winning_line() {
+        for &i in &line {
+            let (cx, cy) = centre(i);
+            shapes.push(format!(
+                r#"<circle cx="{cx}" cy="{cy}" r="{radius}" fill="none" stroke="{GOLD}" stroke-width="4"/>"#
+            ));
+        }
+        if let (Some(&first), Some(&last)) = (line.first(), line.last()) {
+            let ((x1, y1), (x2, y2)) = (centre(first), centre(last));
+            shapes.push(format!(
+                r#"<line x1="{x1}" y1="{y1}" x2="{x2}" y2="{y2}" stroke="{GOLD}" stroke-width="6" stroke-linecap="round"/>"#
+            ));
+        }
+    }
+
+    let shapes = shapes.join("\n  ");
+    formatdoc! {r##"
+        <svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="0 0 {width} {height}">
+          <rect width="{width}" height="{height}" fill="#0d0d0d"/>
+          {shapes}
+        </svg>
+        "##}
+}
+
+/// Draws an SVG from [`svg`] as a PNG.
+pub fn png(svg: &str) -> Result<Vec<u8>, String> {
+    let tree = usvg::Tree::from_str(svg, &usvg::Options::default()).map_err(|e| e.to_string())?;
+    let size = tree.size().to_int_size();
+    let mut pixmap = tiny_skia::Pixmap::new(size.width(), size.height())
+        .ok_or("the board has no area to draw on")?;
+    resvg::render(&tree, tiny_skia::Transform::default(), &mut pixmap.as_mut());
+    pixmap.encode_png().map_err(|e| e.to_string())
+}
+
+/// A wall square, `x` and `y` counting the left wall as column 0.
+fn wall(x: usize, y: usize) -> String {
+    format!(
+        r#"<rect x="{}" y="{}" width="{CELL}" height="{CELL}" fill="{WALL}"/>"#,
+        x * CELL,
+        y * CELL
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_picture() {
+        let mut b = Board::new();
+        assert!(!svg(&b).contains("<line"));
+        for column in 1..=4 {
+            b.place(Team::Cookie, column).unwrap();
+        }
+        let svg = svg(&b);
+        // Walls on both sides and the bottom, 16 holes or pieces and 4 rings round the winners
+        assert_eq!(svg.matches("<rect").count(), 1 + 4 * 2 + 6);
+        assert_eq!(svg.matches("<circle").count(), 16 + 4);
+        assert!(svg.contains("<line"));
+        assert!(png(&svg).unwrap().starts_with(b"\x89PNG"));
+    }
+}
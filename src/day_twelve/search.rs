@@ -0,0 +1,316 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use serde::Deserialize;
+
+use super::{
+    bitboard::{self, Bitboard},
+    Board, State, Team,
+};
+
+/// How hard the search may think before answering.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Limits {
+    /// Deepest search, in moves.
+    pub depth: u32,
+    /// Wall-clock budget. The deepest search finished in time is the one that counts.
+    #[serde(rename = "ms", with = "millis")]
+    pub time: Duration,
+}
+
+impl Limits {
+    pub const MAX_DEPTH: u32 = 16;
+    pub const MAX_TIME: Duration = Duration::from_secs(5);
+
+    /// Keeps client-supplied limits within what the server is willing to spend.
+    pub fn clamped(&self) -> Self {
+        Self {
+            depth: self.depth.clamp(1, Self::MAX_DEPTH),
+            time: self.time.min(Self::MAX_TIME),
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            depth: 8,
+            time: Duration::from_millis(500),
+        }
+    }
+}
+
+mod millis {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer};
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+        Ok(Duration::from_millis(u64::deserialize(d)?))
+    }
+}
+
+const WIN: i64 = 1_000_000;
+/// Scores this close to [`WIN`] are wins found by the search rather than guesses.
+const FORCED: i64 = WIN - Limits::MAX_DEPTH as i64 - 1;
+
+/// What a search of a position found, kept in case the position comes round again.
+struct Entry {
+    depth: u32,
+    score: i64,
+    bound: Bound,
+    column: Option<usize>,
+}
+
+/// How `Entry::score` relates to the real score, after alpha-beta cut the search short.
+enum Bound {
+    Exact,
+    /// At least this good.
+    Lower,
+    /// At most this good.
+    Upper,
+}
+
+/// A copy of a [`Board`] cut down to what the search needs, so it can run without the lock.
+pub struct Position {
+    board: Bitboard,
+    /// Every winning line, as bits of `board`, for scoring positions.
+    lines: Vec<Vec<usize>>,
+    /// Positions already searched, by [`Bitboard::hash`] and who's to move.
+    table: HashMap<u64, Entry>,
+}
+
+impl Position {
+    /// Searches rarely need this many, and forgetting them all is cheaper than picking some.
+    const TABLE_SIZE: usize = 1 << 20;
+    /// Mixed into the hash when it's cookie's move.
+    const COOKIE_TO_MOVE: u64 = 0x5bd1_e995_3c6e_f372;
+
+    pub fn new(b: &Board) -> Self {
+        let board = Bitboard::new(b);
+        Self {
+            lines: board.lines(),
+            board,
+            table: HashMap::new(),
+        }
+    }
+
+    /// The best column (numbered from 1) for `team` to play, or `None` if the game is over.
+    pub fn best_move(self, team: &Team, limits: &Limits) -> Option<u8> {
+        self.analyse(team, limits).map(|(column, _)| column)
+    }
+
+    /// The best column for `team` as in [`Position::best_move`], and how many moves of its own
+    /// `team` needs to win from there, if the search found it can't be stopped.
+    pub fn analyse(mut self, team: &Team, limits: &Limits) -> Option<(u8, Option<u32>)> {
+        if *self.board.state() != State::Incomplete {
+            return None;
+        }
+
+        let deadline = Instant::now() + limits.time;
+        let mut best = None;
+        // Deepen one move at a time, keeping the last answer that finished before the deadline
+        for depth in 1..=limits.depth {
+            let Some((column, score)) = self.root(team, depth, deadline) else {
+                break;
+            };
+            best = Some((column, score));
+            if score.abs() >= FORCED {
+                break;
+            }
+        }
+
+        // Even with no time to think, any legal move beats none
+        let best = best.or_else(|| {
+            (0..self.board.width())
+                .find(|&c| self.board.can_drop(c))
+                .map(|column| (column, 0))
+        });
+        // A win on move `ply` (from 0) scores `WIN - ply`, and every other move is `team`'s
+        best.map(|(column, score)| {
+            let win_in = (score >= FORCED).then(|| ((WIN - score) / 2 + 1) as u32);
+            (column as u8 + 1, win_in)
+        })
+    }
+
+    fn root(&mut self, team: &Team, depth: u32, deadline: Instant) -> Option<(usize, i64)> {
+        let mut best: Option<(usize, i64)> = None;
+        let mut alpha = -WIN - 1;
+        for column in self.column_order(team) {
+            if !self.board.can_drop(column) {
+                continue;
+            }
+            self.board.drop(column, team);
+            let score = if *self.board.state() != State::Incomplete {
+                Some(self.final_score())
+            } else {
+                self.negamax(&team.other(), depth - 1, 1, -WIN - 1, -alpha, deadline)
+                    .map(|s| -s)
+            };
+            self.board.undo();
+
+            let score = score?;
+            if best.is_none_or(|(_, b)| score > b) {
+                best = Some((column, score));
+                alpha = alpha.max(score);
+            }
+        }
+
+        best
+    }
+
+    /// Score for `team` to move, or `None` if the deadline passed.
+    fn negamax(
+        &mut self,
+        team: &Team,
+        depth: u32,
+        ply: i64,
+        mut alpha: i64,
+        mut beta: i64,
+        deadline: Instant,
+    ) -> Option<i64> {
+        if Instant::now() >= deadline {
+            return None;
+        }
+        if depth == 0 {
+            return Some(self.evaluate(team));
+        }
+
+        let key = self.key(team);
+        let start = alpha;
+        if let Some(entry) = self.table.get(&key).filter(|entry| entry.depth >= depth) {
+            let score = from_table(entry.score, ply);
+            match entry.bound {
+                Bound::Exact => return Some(score),
+                Bound::Lower => alpha = alpha.max(score),
+                Bound::Upper => beta = beta.min(score),
+            }
+            if alpha >= beta {
+                return Some(score);
+            }
+        }
+
+        let mut best: Option<(usize, i64)> = None;
+        for column in self.column_order(team) {
+            if !self.board.can_drop(column) {
+                continue;
+            }
+            self.board.drop(column, team);
+            let score = if *self.board.state() != State::Incomplete {
+                // Sooner wins score higher
+                Some(self.final_score().signum() * (WIN - ply))
+            } else {
+                self.negamax(&team.other(), depth - 1, ply + 1, -beta, -alpha, deadline)
+                    .map(|s| -s)
+            };
+            self.board.undo();
+
+            let score = score?;
+            if best.is_none_or(|(_, b)| score > b) {
+                best = Some((column, score));
+            }
+            alpha = alpha.max(score);
+            if alpha >= beta {
+                break;
+            }
+        }
+
+        // No moves left means the board is full
+        let (column, score) = best.map_or((None, 0), |(column, score)| (Some(column), score));
+        let bound = if score <= start {
+            Bound::Upper
+        } else if score >= beta {
+            Bound::Lower
+        } else {
+            Bound::Exact
+        };
+        if self.table.len() >= Self::TABLE_SIZE {
+            self.table.clear();
+        }
+        self.table.insert(
+            key,
+            Entry {
+                depth,
+                score: to_table(score, ply),
+                bound,
+                column,
+            },
+        );
+        Some(score)
+    }
+
+    fn key(&self, team: &Team) -> u64 {
+        match team {
+            Team::Milk => self.board.hash(),
+            _ => self.board.hash() ^ Self::COOKIE_TO_MOVE,
+        }
+    }
+
+    /// The column that did best here last time, then the rest from the middle outwards, where
+    /// the better moves usually are.
+    fn column_order(&self, team: &Team) -> Vec<usize> {
+        let width = self.board.width();
+        let mut columns: Vec<usize> = (0..width).collect();
+        let middle = (width as isize - 1) / 2;
+        columns.sort_by_key(|&c| (c as isize - middle).abs());
+        let remembered = self
+            .table
+            .get(&self.key(team))
+            .and_then(|entry| entry.column);
+        if let Some(i) = remembered.and_then(|c| columns.iter().position(|&x| x == c)) {
+            columns[..=i].rotate_right(1);
+        }
+        columns
+    }
+
+    /// [`WIN`] if the move just made won, otherwise the board's full and it's a draw.
+    fn final_score(&self) -> i64 {
+        match self.board.state() {
+            State::None => 0,
+            _ => WIN,
+        }
+    }
+
+    /// Lines still open to one team count in its favour, more so the fuller they are.
+    fn evaluate(&self, team: &Team) -> i64 {
+        let mine = bitboard::index(team);
+        let mut score = 0;
+        for line in &self.lines {
+            let (mut ours, mut theirs) = (0i64, 0i64);
+            for &bit in line {
+                match self.board.owner(bit) {
+                    None => (),
+                    Some(owner) if owner == mine => ours += 1,
+                    Some(_) => theirs += 1,
+                }
+            }
+            match (ours, theirs) {
+                (n, 0) => score += n * n,
+                (0, n) => score -= n * n,
+                _ => (),
+            }
+        }
+        score
+    }
+}
+
+/// Wins are stored counting from the position they're found in, so they still mean the same
+/// when it's reached in a different number of moves.
+fn to_table(score: i64, ply: i64) -> i64 {
+    if score.abs() >= FORCED {
+        score + score.signum() * ply
+    } else {
+        score
+    }
+}
+
+fn from_table(score: i64, ply: i64) -> i64 {
+    if score.abs() >= FORCED {
+        score - score.signum() * ply
+    } else {
+        score
+    }
+}
@@ -0,0 +1,628 @@
+use std::{
+    net::IpAddr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use actix_web::{
+    get, post,
+    web::{self, Data, Json, Query},
+    HttpRequest, HttpResponse, Responder,
+};
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+
+use super::{
+    search::{Limits, Position},
+    Board, BoardView, GameOptions, State, Team, Tile,
+};
+
+/// Plays bots against each other and keeps their ratings in the `bots` table.
+pub struct Tournament {
+    pool: PgPool,
+    /// Set while a round robin is being played, so only one runs at a time.
+    running: AtomicBool,
+    client: reqwest::Client,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    /// Any column with room.
+    Random,
+    /// The search, looking a single move ahead.
+    Greedy,
+    /// The search behind `/12/suggest`.
+    Search,
+    /// Asks a server of its own for every move.
+    Http,
+}
+
+impl Kind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Kind::Random => "random",
+            Kind::Greedy => "greedy",
+            Kind::Search => "search",
+            Kind::Http => "http",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "random" => Some(Kind::Random),
+            "greedy" => Some(Kind::Greedy),
+            "search" => Some(Kind::Search),
+            "http" => Some(Kind::Http),
+            _ => None,
+        }
+    }
+}
+
+/// A bot as registered at `POST /12/bots`.
+#[derive(Deserialize)]
+pub struct NewBot {
+    name: String,
+    kind: Kind,
+    /// How deep a `search` bot looks, up to [`Limits::MAX_DEPTH`].
+    depth: Option<u32>,
+    /// Where an `http` bot is asked for moves. It has to be on this machine.
+    url: Option<String>,
+}
+
+#[derive(sqlx::FromRow)]
+struct Bot {
+    name: String,
+    kind: String,
+    depth: Option<i32>,
+    url: Option<String>,
+    rating: f64,
+}
+
+#[derive(sqlx::FromRow, Serialize)]
+pub struct Standing {
+    name: String,
+    kind: String,
+    rating: f64,
+    wins: i32,
+    draws: i32,
+    losses: i32,
+}
+
+/// What an `http` bot is sent, and the answer it has to give.
+#[derive(Serialize)]
+struct BotRequest<'a> {
+    team: &'a Team,
+    notation: String,
+    board: BoardView,
+}
+
+#[derive(Deserialize)]
+struct BotMove {
+    column: u8,
+}
+
+struct Outcome {
+    winner: Option<Team>,
+    moves: usize,
+    forfeit: bool,
+}
+
+impl Tournament {
+    /// How much one game can move a rating.
+    const K: f64 = 32.0;
+    /// How long an `http` bot gets to answer.
+    const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+    const MAX_ROUNDS: usize = 10;
+    /// Every bot plays every other one, so a tournament grows with the square of this.
+    const MAX_BOTS: i64 = 16;
+
+    pub fn new(pool: PgPool) -> Self {
+        Self {
+            pool,
+            running: AtomicBool::new(false),
+            // A redirect could send a bot's request anywhere, past the checks on its url
+            client: reqwest::Client::builder()
+                .redirect(reqwest::redirect::Policy::none())
+                .build()
+                .expect("failed to build the bot client"),
+        }
+    }
+
+    /// Plays every bot against every other, `rounds` times with each team, one game at a time.
+    async fn round_robin(&self, bots: Vec<Bot>, options: TournamentOptions) {
+        for (milk, cookie) in pairings(&bots, options.rounds) {
+            if let Err(e) = self.play(milk, cookie, &options).await {
+                println!("Failed to record {} vs {}: {e}", milk.name, cookie.name);
+            }
+        }
+        println!("Tournament between {} bots is over", bots.len());
+    }
+
+    /// How far milk's rating moves for `score` (1 for a win, 0.5 for a draw) against cookie.
+    /// Cookie's moves the same amount the other way.
+    fn rating_change(milk_rating: f64, cookie_rating: f64, score: f64) -> f64 {
+        let expected = 1.0 / (1.0 + 10f64.powf((cookie_rating - milk_rating) / 400.0));
+        Self::K * (score - expected)
+    }
+
+    async fn play(
+        &self,
+        milk: &Bot,
+        cookie: &Bot,
+        options: &TournamentOptions,
+    ) -> Result<(), sqlx::Error> {
+        let mut b = options.board();
+        let time = Duration::from_millis(options.ms).min(Limits::MAX_TIME);
+
+        let mut team = Team::Milk;
+        let outcome = loop {
+            match b.winner() {
+                State::Incomplete => (),
+                State::None => break Outcome::draw(&b),
+                state => break Outcome::won(state.team().expect("someone won"), &b),
+            }
+
+            let bot = if team == Team::Milk { milk } else { cookie };
+            let column = self.choose(bot, &b, &team, time).await;
+            if column.is_none_or(|column| b.place(team.clone(), column).is_err()) {
+                break Outcome {
+                    winner: Some(team.other()),
+                    moves: b.history.len(),
+                    forfeit: true,
+                };
+            }
+            team = team.other();
+        };
+
+        self.record(milk, cookie, &outcome).await
+    }
+
+    /// The column `bot` plays for `team`, or `None` if it has no answer.
+    async fn choose(&self, bot: &Bot, b: &Board, team: &Team, time: Duration) -> Option<u8> {
+        let limits = match Kind::parse(&bot.kind)? {
+            Kind::Random => {
+                let open: Vec<u8> = (0..b.width)
+                    .filter(|&x| b.board[x] == Tile::Empty)
+                    .map(|x| x as u8 + 1)
+                    .collect();
+                return open.choose(&mut rand::thread_rng()).copied();
+            }
+            Kind::Http => return self.ask(bot.url.as_deref()?, b, team).await,
+            Kind::Greedy => Limits { depth: 1, time },
+            Kind::Search => Limits {
+                depth: bot.depth.map_or(Limits::default().depth, |d| d as u32),
+                time,
+            },
+        };
+
+        let position = Position::new(b);
+        let team = team.clone();
+        let limits = limits.clamped();
+        web::block(move || position.best_move(&team, &limits))
+            .await
+            .ok()
+            .flatten()
+    }
+
+    async fn ask(&self, url: &str, b: &Board, team: &Team) -> Option<u8> {
+        // Its name may point somewhere else since the bot was added
+        if !is_public(url).await {
+            println!("Bot at {url} isn't on a public host");
+            return None;
+        }
+        let request = BotRequest {
+            team,
+            notation: b.notation(),
+            board: BoardView::new(b),
+        };
+        let body = serde_json::to_string(&request).ok()?;
+        let res = self
+            .client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .timeout(Self::HTTP_TIMEOUT)
+            .send()
+            .await;
+        let text = match res {
+            Ok(res) if res.status().is_redirection() => {
+                println!("Bot at {url} answered with a redirect");
+                return None;
+            }
+            Ok(res) => res.text().await.ok()?,
+            Err(e) => {
+                println!("Bot at {url} didn't answer: {e}");
+                return None;
+            }
+        };
+        serde_json::from_str::<BotMove>(&text)
+            .ok()
+            .map(|m| m.column)
+    }
+
+    /// Saves the game and moves both ratings.
+    async fn record(&self, milk: &Bot, cookie: &Bot, outcome: &Outcome) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        // Read the ratings again, they've changed since the bots were loaded
+        let ratings: Vec<(String, f64)> =
+            sqlx::query_as("SELECT name, rating FROM bots WHERE name = $1 OR name = $2 FOR UPDATE")
+                .bind(&milk.name)
+                .bind(&cookie.name)
+                .fetch_all(&mut *tx)
+                .await?;
+        let rating = |bot: &Bot| {
+            ratings
+                .iter()
+                .find(|(name, _)| *name == bot.name)
+                .map_or(bot.rating, |&(_, rating)| rating)
+        };
+        let (milk_rating, cookie_rating) = (rating(milk), rating(cookie));
+
+        // 1 for a win, 0.5 for a draw, from milk's side
+        let score = match outcome.winner {
+            Some(Team::Milk) => 1.0,
+            // Tournaments are classic games, so chocolate never plays
+            Some(Team::Cookie | Team::Chocolate) => 0.0,
+            None => 0.5,
+        };
+        let change = Self::rating_change(milk_rating, cookie_rating, score);
+
+        for (bot, rating, score) in [
+            (milk, milk_rating + change, score),
+            (cookie, cookie_rating - change, 1.0 - score),
+        ] {
+            sqlx::query(
+                "UPDATE bots SET
+                    rating = $2,
+                    wins = wins + ($3 = 1.0)::INT,
+                    draws = draws + ($3 = 0.5)::INT,
+                    losses = losses + ($3 = 0.0)::INT
+                 WHERE name = $1",
+            )
+            .bind(&bot.name)
+            .bind(rating)
+            .bind(score)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        let winner = outcome.winner.as_ref().map(|team| match team {
+            Team::Milk => &milk.name,
+            Team::Cookie | Team::Chocolate => &cookie.name,
+        });
+        sqlx::query(
+            "INSERT INTO bot_matches (milk, cookie, winner, moves, forfeit) VALUES ($1, $2, $3, $4, $5)",
+        )
+        .bind(&milk.name)
+        .bind(&cookie.name)
+        .bind(winner)
+        .bind(outcome.moves as i32)
+        .bind(outcome.forfeit)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await
+    }
+}
+
+impl Outcome {
+    fn won(team: Team, b: &Board) -> Self {
+        Self {
+            winner: Some(team),
+            moves: b.history.len(),
+            forfeit: false,
+        }
+    }
+
+    fn draw(b: &Board) -> Self {
+        Self {
+            winner: None,
+            moves: b.history.len(),
+            forfeit: false,
+        }
+    }
+}
+
+/// Every game of a round robin as (milk, cookie): each bot plays every other `rounds` times
+/// with each team.
+fn pairings(bots: &[Bot], rounds: usize) -> Vec<(&Bot, &Bot)> {
+    let round: Vec<(&Bot, &Bot)> = bots
+        .iter()
+        .flat_map(|milk| {
+            bots.iter()
+                .filter(move |cookie| cookie.name != milk.name)
+                .map(move |cookie| (milk, cookie))
+        })
+        .collect();
+    round.repeat(rounds)
+}
+
+/// Clears [`Tournament::running`] when dropped, so a tournament that dies part way through
+/// doesn't stop the next one from starting.
+struct Running<'a>(&'a AtomicBool);
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+/// Whether `url` is plain HTTP to a host on the internet. Bots are called from the server, so
+/// one on loopback or a private network could reach what's only meant to be reachable from it.
+async fn is_public(url: &str) -> bool {
+    let Ok(url) = reqwest::Url::parse(url) else {
+        return false;
+    };
+    let (Some(host), Some(port)) = (url.host_str(), url.port_or_known_default()) else {
+        return false;
+    };
+    if url.scheme() != "http" {
+        return false;
+    }
+    let ips = match host.trim_start_matches('[').trim_end_matches(']').parse() {
+        Ok(ip) => vec![ip],
+        Err(_) => match tokio::net::lookup_host((host, port)).await {
+            Ok(addrs) => addrs.map(|addr| addr.ip()).collect(),
+            Err(_) => return false,
+        },
+    };
+    !ips.is_empty() && ips.iter().all(is_public_ip)
+}
+
+fn is_public_ip(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                // Carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && b & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_ip(&IpAddr::V4(ip)),
+            None => {
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local()
+                    || ip.is_multicast())
+            }
+        },
+    }
+}
+
+#[post("/12/bots")]
+pub async fn add_bot(
+    bot: Json<NewBot>,
+    tournament: Data<Tournament>,
+    req: HttpRequest,
+) -> impl Responder {
+    if !crate::day_sixteen::is_admin(&req) {
+        return HttpResponse::Forbidden().finish();
+    }
+    if bot.name.is_empty() || bot.name.len() > 64 {
+        return HttpResponse::BadRequest().body("name must be 1 to 64 characters\n");
+    }
+    match (bot.kind, &bot.url) {
+        (Kind::Http, Some(url)) if is_public(url).await => (),
+        (Kind::Http, _) => {
+            return HttpResponse::BadRequest().body("http bots need an http url on a public host\n")
+        }
+        (_, Some(_)) => return HttpResponse::BadRequest().body("only http bots take a url\n"),
+        _ => (),
+    }
+    if bot
+        .depth
+        .is_some_and(|d| !(1..=Limits::MAX_DEPTH).contains(&d))
+    {
+        return HttpResponse::BadRequest().body(format!(
+            "depth must be between 1 and {}\n",
+            Limits::MAX_DEPTH
+        ));
+    }
+
+    let bots: Result<i64, _> = sqlx::query_scalar("SELECT count(*) FROM bots")
+        .fetch_one(&tournament.pool)
+        .await;
+    match bots {
+        Ok(bots) if bots >= Tournament::MAX_BOTS => {
+            return HttpResponse::Conflict()
+                .body(format!("There are already {} bots\n", Tournament::MAX_BOTS))
+        }
+        Ok(_) => (),
+        Err(e) => {
+            println!("Failed to count bots: {e}");
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    let res = sqlx::query(
+        "INSERT INTO bots (name, kind, depth, url) VALUES ($1, $2, $3, $4) ON CONFLICT DO NOTHING",
+    )
+    .bind(&bot.name)
+    .bind(bot.kind.as_str())
+    .bind(bot.depth.map(|d| d as i32))
+    .bind(&bot.url)
+    .execute(&tournament.pool)
+    .await;
+    match res {
+        Ok(done) if done.rows_affected() == 0 => HttpResponse::Conflict().finish(),
+        Ok(_) => HttpResponse::Created().finish(),
+        Err(e) => {
+            println!("Failed to add bot: {e}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// The board and pace for a tournament's games.
+#[derive(Deserialize)]
+pub struct TournamentOptions {
+    width: Option<usize>,
+    height: Option<usize>,
+    connect: Option<usize>,
+    #[serde(default = "TournamentOptions::default_rounds")]
+    rounds: usize,
+    /// Thinking time per move for the searching bots.
+    #[serde(default = "TournamentOptions::default_ms")]
+    ms: u64,
+}
+
+impl TournamentOptions {
+    fn default_rounds() -> usize {
+        1
+    }
+
+    fn default_ms() -> u64 {
+        100
+    }
+
+    fn game_options(&self) -> GameOptions {
+        GameOptions {
+            width: self.width,
+            height: self.height,
+            connect: self.connect,
+            strict: true,
+            ..GameOptions::default()
+        }
+    }
+
+    fn board(&self) -> Board {
+        self.game_options()
+            .build()
+            .expect("options were checked before the tournament started")
+    }
+}
+
+#[post("/12/tournament")]
+pub async fn start_tournament(
+    options: Query<TournamentOptions>,
+    tournament: Data<Tournament>,
+) -> impl Responder {
+    let options = options.into_inner();
+    if let Err(e) = options.game_options().build() {
+        return HttpResponse::BadRequest().body(e);
+    }
+    if !(1..=Tournament::MAX_ROUNDS).contains(&options.rounds) {
+        return HttpResponse::BadRequest().body(format!(
+            "rounds must be between 1 and {}\n",
+            Tournament::MAX_ROUNDS
+        ));
+    }
+
+    let bots: Vec<Bot> =
+        match sqlx::query_as("SELECT name, kind, depth, url, rating FROM bots ORDER BY name")
+            .fetch_all(&tournament.pool)
+            .await
+        {
+            Ok(bots) => bots,
+            Err(e) => {
+                println!("Failed to load bots: {e}");
+                return HttpResponse::InternalServerError().finish();
+            }
+        };
+    if bots.len() < 2 {
+        return HttpResponse::BadRequest().body("a tournament needs at least two bots\n");
+    }
+    if tournament.running.swap(true, Ordering::AcqRel) {
+        return HttpResponse::Conflict().body("a tournament is already running\n");
+    }
+
+    let games = bots.len() * (bots.len() - 1) * options.rounds;
+    let names: Vec<String> = bots.iter().map(|bot| bot.name.clone()).collect();
+    let tournament = tournament.into_inner();
+    actix_web::rt::spawn(async move {
+        let _running = Running(&tournament.running);
+        tournament.round_robin(bots, options).await;
+    });
+
+    HttpResponse::Accepted().json(serde_json::json!({ "bots": names, "games": games }))
+}
+
+#[get("/12/leaderboard")]
+pub async fn leaderboard(tournament: Data<Tournament>) -> impl Responder {
+    let standings: Result<Vec<Standing>, _> = sqlx::query_as(
+        "SELECT name, kind, rating, wins, draws, losses FROM bots ORDER BY rating DESC, name",
+    )
+    .fetch_all(&tournament.pool)
+    .await;
+    match standings {
+        Ok(standings) => HttpResponse::Ok().json(standings),
+        Err(e) => {
+            println!("Failed to load leaderboard: {e}");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rating_change() {
+        // Evenly matched bots trade half of K
+        assert_eq!(Tournament::rating_change(1200.0, 1200.0, 1.0), 16.0);
+        assert_eq!(Tournament::rating_change(1200.0, 1200.0, 0.5), 0.0);
+        assert_eq!(Tournament::rating_change(1200.0, 1200.0, 0.0), -16.0);
+
+        // Beating a much stronger bot is worth nearly all of it, and losing to one very little
+        let upset = Tournament::rating_change(1200.0, 1600.0, 1.0);
+        assert!((upset - 29.09).abs() < 0.01);
+        let expected = Tournament::rating_change(1200.0, 1600.0, 0.0);
+        assert!((expected + 2.91).abs() < 0.01);
+        // A draw moves the weaker bot up
+        assert!(Tournament::rating_change(1200.0, 1600.0, 0.5) > 0.0);
+    }
+
+    #[test]
+    fn test_pairings() {
+        let bot = |name: &str| Bot {
+            name: name.to_string(),
+            kind: "random".to_string(),
+            depth: None,
+            url: None,
+            rating: 1200.0,
+        };
+        let bots = [bot("a"), bot("b"), bot("c")];
+        let names = |rounds| {
+            pairings(&bots, rounds)
+                .into_iter()
+                .map(|(milk, cookie)| format!("{}{}", milk.name, cookie.name))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(names(1), ["ab", "ac", "ba", "bc", "ca", "cb"]);
+        let twice = names(2);
+        assert_eq!(twice.len(), bots.len() * (bots.len() - 1) * 2);
+        assert_eq!(twice[..6], twice[6..]);
+        assert!(names(0).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_public_hosts() {
+        for url in [
+            "http://localhost:8000/move",
+            "http://127.0.0.1/",
+            "http://[::1]/",
+            "http://[::ffff:127.0.0.1]/",
+            "http://10.1.2.3/",
+            "http://192.168.0.10:8080/",
+            "http://169.254.169.254/latest/meta-data",
+            "http://100.100.0.1/",
+            "http://[fd12::1]/",
+            "https://1.1.1.1/",
+            "not a url",
+        ] {
+            assert!(!is_public(url).await, "{url}");
+        }
+        assert!(is_public("http://1.1.1.1/").await);
+        assert!(is_public("http://[2606:4700::1111]:8000/").await);
+    }
+}
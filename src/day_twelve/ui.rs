@@ -0,0 +1,345 @@
+use actix_web::{
+    body, get, post,
+    web::{Data, Path},
+    HttpRequest, HttpResponse, Responder,
+};
+use indoc::formatdoc;
+use uuid::Uuid;
+
+use super::{lobby, place_in, reset_in, Action, Board, BoardData, State, Team, Variant};
+
+#[get("/12/ui")]
+pub async fn ui_page(board_data: Data<BoardData>) -> impl Responder {
+    page_in(&board_data, BoardData::DEFAULT_GAME)
+}
+
+#[get("/12/ui/board")]
+pub async fn ui_board(board_data: Data<BoardData>) -> impl Responder {
+    board_in(&board_data, BoardData::DEFAULT_GAME, None)
+}
+
+#[post("/12/ui/place/{column}")]
+pub async fn ui_place(
+    column: Path<u8>,
+    board_data: Data<BoardData>,
+    req: HttpRequest,
+) -> impl Responder {
+    let column = column.into_inner();
+    play_in(
+        &board_data,
+        &req,
+        BoardData::DEFAULT_GAME,
+        column,
+        Action::Drop,
+    )
+    .await
+}
+
+#[post("/12/ui/place/{column}/{row}")]
+pub async fn ui_place_at(
+    path: Path<(u8, u8)>,
+    board_data: Data<BoardData>,
+    req: HttpRequest,
+) -> impl Responder {
+    let (column, row) = path.into_inner();
+    let action = Action::Put { row };
+    play_in(&board_data, &req, BoardData::DEFAULT_GAME, column, action).await
+}
+
+#[post("/12/ui/pop/{column}")]
+pub async fn ui_pop(
+    column: Path<u8>,
+    board_data: Data<BoardData>,
+    req: HttpRequest,
+) -> impl Responder {
+    let column = column.into_inner();
+    play_in(
+        &board_data,
+        &req,
+        BoardData::DEFAULT_GAME,
+        column,
+        Action::Pop,
+    )
+    .await
+}
+
+#[post("/12/ui/reset")]
+pub async fn ui_reset(board_data: Data<BoardData>, req: HttpRequest) -> impl Responder {
+    restart_in(&board_data, &req, BoardData::DEFAULT_GAME).await
+}
+
+#[get("/12/games/{id}/ui")]
+pub async fn game_ui_page(id: Path<Uuid>, board_data: Data<BoardData>) -> impl Responder {
+    page_in(&board_data, id.into_inner())
+}
+
+#[get("/12/games/{id}/ui/board")]
+pub async fn game_ui_board(id: Path<Uuid>, board_data: Data<BoardData>) -> impl Responder {
+    board_in(&board_data, id.into_inner(), None)
+}
+
+#[post("/12/games/{id}/ui/place/{column}")]
+pub async fn game_ui_place(
+    path: Path<(Uuid, u8)>,
+    board_data: Data<BoardData>,
+    req: HttpRequest,
+) -> impl Responder {
+    let (id, column) = path.into_inner();
+    play_in(&board_data, &req, id, column, Action::Drop).await
+}
+
+#[post("/12/games/{id}/ui/place/{column}/{row}")]
+pub async fn game_ui_place_at(
+    path: Path<(Uuid, u8, u8)>,
+    board_data: Data<BoardData>,
+    req: HttpRequest,
+) -> impl Responder {
+    let (id, column, row) = path.into_inner();
+    play_in(&board_data, &req, id, column, Action::Put { row }).await
+}
+
+#[post("/12/games/{id}/ui/pop/{column}")]
+pub async fn game_ui_pop(
+    path: Path<(Uuid, u8)>,
+    board_data: Data<BoardData>,
+    req: HttpRequest,
+) -> impl Responder {
+    let (id, column) = path.into_inner();
+    play_in(&board_data, &req, id, column, Action::Pop).await
+}
+
+#[post("/12/games/{id}/ui/reset")]
+pub async fn game_ui_reset(
+    id: Path<Uuid>,
+    board_data: Data<BoardData>,
+    req: HttpRequest,
+) -> impl Responder {
+    restart_in(&board_data, &req, id.into_inner()).await
+}
+
+/// Where a game's routes live.
+fn base(id: Uuid) -> String {
+    if id == BoardData::DEFAULT_GAME {
+        "/12".to_string()
+    } else {
+        format!("/12/games/{id}")
+    }
+}
+
+/// The whole page, with the board rendered in so it works before htmx has loaded.
+fn page_in(board_data: &BoardData, id: Uuid) -> HttpResponse {
+    let Some(game) = board_data.game(id) else {
+        return HttpResponse::NotFound().finish();
+    };
+    let board = fragment(id, &game.board.lock().unwrap(), None);
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(formatdoc! {r#"
+            <!DOCTYPE html>
+            <html>
+              <head>
+                <meta charset="utf-8">
+                <title>Milk and cookies</title>
+                <script src="https://unpkg.com/htmx.org@2.0.4"></script>
+                <link rel="stylesheet" href="/assets/12.css">
+              </head>
+              <body>
+                <main>
+                  <h1>🥛 Milk and cookies 🍪</h1>
+                  {board}
+                </main>
+              </body>
+            </html>
+            "#})
+}
+
+fn board_in(board_data: &BoardData, id: Uuid, error: Option<&str>) -> HttpResponse {
+    let Some(game) = board_data.game(id) else {
+        return HttpResponse::NotFound().finish();
+    };
+    let board = fragment(id, &game.board.lock().unwrap(), error);
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(board)
+}
+
+/// Plays for whoever's turn it is, or the player's own team in lobby games. A move that's
+/// turned down still swaps in the board, with the reason under it, since htmx leaves the page
+/// alone on errors.
+async fn play_in(
+    board_data: &BoardData,
+    req: &HttpRequest,
+    id: Uuid,
+    column: u8,
+    action: Action,
+) -> HttpResponse {
+    let Some(game) = board_data.game(id) else {
+        return HttpResponse::NotFound().finish();
+    };
+    let seat = lobby::player(id, req).filter(|_| game.seated.is_some());
+    let team = seat
+        .clone()
+        .unwrap_or_else(|| to_play(&game.board.lock().unwrap()));
+    if let Err(e) = lobby::check_seat(&game, seat.as_ref(), Some(&team)) {
+        return board_in(board_data, id, Some(&e.to_string()));
+    }
+
+    let res = place_in(board_data, id, team, column, action, true).await;
+    let error = if res.status().is_success() {
+        None
+    } else {
+        let body = body::to_bytes(res.into_body()).await.unwrap_or_default();
+        let error: serde_json::Value = serde_json::from_slice(&body).unwrap_or_default();
+        Some(
+            error["reason"]
+                .as_str()
+                .unwrap_or("That move can't be made")
+                .to_string(),
+        )
+    };
+    board_in(board_data, id, error.as_deref())
+}
+
+/// Starts the game over on an empty board of the same kind.
+async fn restart_in(board_data: &BoardData, req: &HttpRequest, id: Uuid) -> HttpResponse {
+    let Some(game) = board_data.game(id) else {
+        return HttpResponse::NotFound().finish();
+    };
+    if let Err(e) = lobby::check_seat(&game, lobby::player(id, req).as_ref(), None) {
+        return board_in(board_data, id, Some(&e.to_string()));
+    }
+    let res = reset_in(board_data, id, None, req).await;
+    if !res.status().is_success() {
+        return res;
+    }
+    board_in(board_data, id, None)
+}
+
+/// Whose move a click makes: the team whose turn it is, or milk to start.
+fn to_play(b: &Board) -> Team {
+    b.next_team().unwrap_or(Team::Milk)
+}
+
+/// The board with a button for every move, who's to play or who won, and a reset button.
+fn fragment(id: Uuid, b: &Board, error: Option<&str>) -> String {
+    let base = base(id);
+    let state = b.winner();
+    let over = state != State::Incomplete;
+    let disabled = if over { " disabled" } else { "" };
+    let winning = b.winning_line().unwrap_or_default();
+    let cell = |i: usize| {
+        let class = if winning.contains(&i) {
+            "cell win"
+        } else {
+            "cell"
+        };
+        format!(r#"<span class="{class}">{}</span>"#, b.board[i])
+    };
+
+    let columns: String = (0..b.width)
+        .map(|x| {
+            let column = x + 1;
+            if b.variant == Variant::NoGravity {
+                let cells: String = (0..b.height)
+                    .map(|y| {
+                        let row = y + 1;
+                        format!(
+                            r#"<button class="put" hx-post="{base}/ui/place/{column}/{row}"{disabled}>{}</button>"#,
+                            cell(y * b.width + x)
+                        )
+                    })
+                    .collect();
+                format!(r#"<div class="column">{cells}</div>"#)
+            } else {
+                let cells: String = (0..b.height).map(|y| cell(y * b.width + x)).collect();
+                format!(
+                    r#"<button class="column" hx-post="{base}/ui/place/{column}"{disabled}>{cells}</button>"#
+                )
+            }
+        })
+        .collect();
+    let pops = if b.variant == Variant::PopOut {
+        let buttons: String = (1..=b.width)
+            .map(|column| {
+                format!(
+                    r#"<button class="pop" hx-post="{base}/ui/pop/{column}"{disabled}>pop</button>"#
+                )
+            })
+            .collect();
+        format!(r#"<div class="pops">{buttons}</div>"#)
+    } else {
+        String::new()
+    };
+
+    let status = match state.team() {
+        Some(team) => format!(r#"<p class="status winner">{team} wins!</p>"#),
+        None if over => r#"<p class="status winner">No winner.</p>"#.to_string(),
+        None => format!(r#"<p class="status">{} to play</p>"#, to_play(b)),
+    };
+    let error = error.map_or(String::new(), |error| {
+        format!(
+            r#"<p class="error">{}</p>"#,
+            html_escape::encode_text(error)
+        )
+    });
+
+    formatdoc! {r#"
+        <div id="board" hx-target="this" hx-swap="outerHTML">
+          {status}
+          <div class="grid">{columns}</div>
+          {pops}
+          {error}
+          <button class="reset" hx-post="{base}/ui/reset">Reset</button>
+        </div>
+        "#}
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::day_twelve::GameOptions;
+
+    #[test]
+    fn test_fragment() {
+        let id = Uuid::new_v4();
+        let mut b = GameOptions {
+            strict: true,
+            ..GameOptions::default()
+        }
+        .build()
+        .unwrap();
+        b.place(Team::Milk, 1).unwrap();
+        let html = fragment(id, &b, None);
+        assert!(html.contains(&format!(
+            r#"<p class="status">{} to play</p>"#,
+            Team::Cookie
+        )));
+        assert!(!html.contains("disabled"));
+
+        for column in [2, 1, 2, 1, 2, 1] {
+            let team = b.next_team().unwrap();
+            b.place(team, column).unwrap();
+        }
+        let html = fragment(id, &b, Some("<b>Game over</b>"));
+        assert!(html.contains(&format!("{} wins!", Team::Milk)));
+        assert!(!html.contains("to play"));
+        assert_eq!(html.matches(r#"class="cell win""#).count(), 4);
+        assert!(html.contains(r#"hx-post="/12/games/"#) && html.contains("disabled"));
+        assert!(html.contains("&lt;b&gt;Game over&lt;/b&gt;"));
+        assert!(!html.contains("<b>"));
+
+        // Milk never opens, and loses on time
+        let mut b = GameOptions {
+            move_time: Some(10),
+            ..GameOptions::default()
+        }
+        .build()
+        .unwrap();
+        b.flag(Instant::now() + Duration::from_secs(11));
+        let html = fragment(id, &b, None);
+        assert!(html.contains(&format!("{} wins!", Team::Cookie)));
+        assert!(html.contains("disabled"));
+    }
+}
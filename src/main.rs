@@ -18,14 +18,33 @@ use shuttle_actix_web::ShuttleActixWeb;
 #[shuttle_runtime::main]
 async fn main(
     #[shuttle_shared_db::Postgres] pool: sqlx::PgPool,
+    #[shuttle_runtime::Metadata] metadata: shuttle_runtime::DeploymentMetadata,
+    #[shuttle_runtime::Secrets] secrets: shuttle_runtime::SecretStore,
 ) -> ShuttleActixWeb<impl FnOnce(&mut ServiceConfig) + Send + Clone + 'static> {
     sqlx::migrate!()
         .run(&pool)
         .await
         .expect("Failed to run migrations");
 
-    let milk_crate = Data::new(day_nine::MilkCrate::new());
-    let board_data = Data::new(day_twelve::board_data());
+    let milk_crate = Data::new(
+        day_nine::MilkCrate::from_env(pool.clone())
+            .await
+            .expect("Failed to set up milk crate"),
+    );
+    let board_data = Data::new(
+        day_twelve::BoardData::load(pool.clone())
+            .await
+            .expect("Failed to load games"),
+    );
+    day_twelve::watch_clocks(board_data.clone());
+    let tournament = Data::new(day_twelve::Tournament::new(pool.clone()));
+    let lobby = Data::new(day_twelve::Lobby::new());
+    // The built-in key is public, so it's only good enough when running locally
+    let development = metadata.env == shuttle_runtime::Environment::Local;
+    let key_source = day_sixteen::KeySource::from_secrets(&secrets);
+    if let Err(e) = day_sixteen::load_keys(key_source, development) {
+        println!("Failed to load gift keys, signing with the built-in key: {e}");
+    }
     let gift_store = Data::new(day_sixteen::GiftStore::new());
     let pool_data = Data::new(pool);
     let token_store = Data::new(day_nineteen::TokenStore::default());
@@ -50,13 +69,69 @@ async fn main(
                 .app_data(milk_crate)
                 .service(day_nine::milk)
                 .service(day_nine::refill)
+                .service(day_nine::get_config)
+                .service(day_nine::set_config)
+                .service(day_nine::stats)
                 .app_data(board_data)
                 .service(day_twelve::board)
                 .service(day_twelve::reset)
                 .service(day_twelve::place)
                 .service(day_twelve::random_board)
+                .service(day_twelve::undo)
+                .service(day_twelve::suggest)
+                .service(day_twelve::create_game)
+                .service(day_twelve::game_board)
+                .service(day_twelve::game_place)
+                .service(day_twelve::game_reset)
+                .service(day_twelve::game_random_board)
+                .service(day_twelve::game_undo)
+                .service(day_twelve::game_suggest)
+                .service(day_twelve::game_moves)
+                .service(day_twelve::game_replay)
+                .service(day_twelve::stream)
+                .service(day_twelve::ws)
+                .service(day_twelve::game_stream)
+                .service(day_twelve::game_ws)
+                .service(day_twelve::export)
+                .service(day_twelve::import)
+                .service(day_twelve::game_export)
+                .service(day_twelve::game_import)
+                .service(day_twelve::random_game)
+                .service(day_twelve::game_random_game)
+                .service(day_twelve::place_at)
+                .service(day_twelve::pop)
+                .service(day_twelve::game_place_at)
+                .service(day_twelve::game_pop)
+                .service(day_twelve::perft)
+                .service(day_twelve::game_perft)
+                .service(day_twelve::analysis)
+                .service(day_twelve::game_analysis)
+                .service(day_twelve::ui_page)
+                .service(day_twelve::ui_board)
+                .service(day_twelve::ui_place)
+                .service(day_twelve::ui_place_at)
+                .service(day_twelve::ui_pop)
+                .service(day_twelve::ui_reset)
+                .service(day_twelve::game_ui_page)
+                .service(day_twelve::game_ui_board)
+                .service(day_twelve::game_ui_place)
+                .service(day_twelve::game_ui_place_at)
+                .service(day_twelve::game_ui_pop)
+                .service(day_twelve::game_ui_reset)
+                .service(day_twelve::board_svg)
+                .service(day_twelve::board_png)
+                .service(day_twelve::game_board_svg)
+                .service(day_twelve::game_board_png)
+                .app_data(tournament)
+                .service(day_twelve::add_bot)
+                .service(day_twelve::start_tournament)
+                .service(day_twelve::leaderboard)
+                .app_data(lobby)
+                .service(day_twelve::join_lobby)
+                .service(day_twelve::lobby_ticket)
                 .service(day_sixteen::wrap)
                 .service(day_sixteen::unwrap)
+                .service(day_sixteen::reload_keys)
                 .app_data(gift_store)
                 .service(day_sixteen::decode)
                 .app_data(pool_data)